@@ -1,44 +1,187 @@
+use std::path::Path;
 use std::path::PathBuf;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     const EMPTY_PATH_SLICE: &[&str] = &[];
+    // the messages of echopb.Echo, which the JSON codec service serializes with serde
+    const ECHO_MESSAGES: [&str; 4] = [
+        "echopb.EchoRequest",
+        "echopb.EchoResponse",
+        "echopb.EchoRepeatRequest",
+        "echopb.EchoCollectResponse",
+    ];
+    // serde adapters for the fields whose protobuf JSON form is not serde's default: the
+    // well-known types, which prost-types does not derive serde for, bytes and 64-bit integers
+    const JSON_FIELDS: [(&str, &str); 11] = [
+        ("echopb.EchoRequest.payload", "base64"),
+        ("echopb.EchoRequest.send_time_unix_nanos", "int_string"),
+        ("echopb.EchoRequest.sequence", "int_string"),
+        ("echopb.EchoRequest.delay", "option_string"),
+        ("echopb.EchoRequest.attributes", "option_struct"),
+        ("echopb.EchoResponse.payload", "base64"),
+        ("echopb.EchoResponse.send_time_unix_nanos", "int_string"),
+        ("echopb.EchoResponse.sequence", "int_string"),
+        ("echopb.EchoResponse.server_receive_time", "option_string"),
+        ("echopb.EchoResponse.server_send_time", "option_string"),
+        ("echopb.EchoResponse.attributes", "option_struct"),
+    ];
 
     dlprotoc::download_protoc()?;
-    tonic_prost_build::compile_protos("proto/echo.proto")?;
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR")?);
+
+    // also write the file descriptor set for the reflection service
+    let mut builder = tonic_prost_build::configure()
+        .file_descriptor_set_path(out_dir.join("echo_descriptor.bin"));
+    for message in ECHO_MESSAGES {
+        builder = builder.message_attribute(
+            message,
+            "#[derive(serde::Serialize, serde::Deserialize)] #[serde(default, rename_all = \"camelCase\")]",
+        );
+    }
+    for (field, module) in JSON_FIELDS {
+        builder = builder.field_attribute(
+            field,
+            format!("#[serde(with = \"crate::json_wkt::{module}\")]"),
+        );
+    }
+    builder.compile_with_config(go_type_names(), &["proto/echo.proto"], &["proto"])?;
 
     // make a copy of the protos with a custom codec
-    let out_dir = PathBuf::from(std::env::var("OUT_DIR")?);
     let custom_codec_dir = out_dir.join("custom_codec");
     // use create_dir_all to ignore "directory exists" errors
     std::fs::create_dir_all(&custom_codec_dir)?;
     tonic_prost_build::configure()
         .out_dir(custom_codec_dir)
         .codec_path("crate::CustomResponseCodec")
-        .compile_protos(&["proto/echo.proto"], EMPTY_PATH_SLICE)?;
+        .compile_with_config(go_type_names(), &["proto/echo.proto"], EMPTY_PATH_SLICE)?;
+
+    // a JSON copy of the service next to the protobuf one
+    build_manual_echo_service(
+        "json.echopb",
+        &EchoMessageTypes::in_module("crate::echopb"),
+        "crate::json_codec::JsonCodec",
+        &out_dir,
+    );
+
+    // a JSON copy with the same name as the protobuf service, for the codec registry
+    let json_codec_dir = out_dir.join("json_codec");
+    std::fs::create_dir_all(&json_codec_dir)?;
+    build_manual_echo_service(
+        "echopb",
+        &EchoMessageTypes::in_module("crate::echopb"),
+        "crate::json_codec::JsonCodec",
+        &json_codec_dir,
+    );
+
+    // a copy of the service that passes the undecoded message bytes to the server
+    let raw_codec_dir = out_dir.join("raw_codec");
+    std::fs::create_dir_all(&raw_codec_dir)?;
+    build_manual_echo_service(
+        "echopb",
+        &EchoMessageTypes::all("bytes::Bytes"),
+        "crate::RawBytesCodec",
+        &raw_codec_dir,
+    );
     Ok(())
 }
 
-// TODO: define a raw buffer output type
-// Manually define the json.helloworld.Greeter service which used a custom JsonCodec to use json
-// serialization instead of protobuf for sending messages on the wire.
-// This will result in generated client and server code which relies on its request, response and
-// codec types being defined in a module `crate::common`.
-//
-// See the client/server examples defined in `src/json-codec` for more information.
-// fn build_json_codec_service() {
-//     let greeter_service = tonic_build::manual::Service::builder()
-//         .name("Greeter")
-//         .package("json.helloworld")
-//         .method(
-//             tonic_build::manual::Method::builder()
-//                 .name("say_hello")
-//                 .route_name("SayHello")
-//                 .input_type("crate::common::HelloRequest")
-//                 .output_type("crate::common::HelloResponse")
-//                 .codec_path("crate::common::JsonCodec")
-//                 .build(),
-//         )
-//         .build();
-
-//     tonic_build::manual::Builder::new().compile(&[greeter_service]);
-// }
+/// Returns a config that implements `prost::Name` for every message, so they can be packed in
+/// `Any`. By default prost's type URLs are `/echopb.Example1`, which does not agree with Go's
+/// protobuf implementation: "The default type URL for a given message type is
+/// type.googleapis.com/_packagename_._messagename_."
+/// <https://protobuf.dev/programming-guides/proto3/#any>
+fn go_type_names() -> tonic_prost_build::Config {
+    let mut config = tonic_prost_build::Config::new();
+    config
+        .enable_type_names()
+        .type_name_domain(["."], "type.googleapis.com");
+    config
+}
+
+/// The Rust types of the messages of a manually defined `Echo` service.
+struct EchoMessageTypes {
+    request: String,
+    response: String,
+    repeat_request: String,
+    collect_response: String,
+}
+
+impl EchoMessageTypes {
+    /// The `echopb` messages generated in `module`.
+    fn in_module(module: &str) -> Self {
+        Self {
+            request: format!("{module}::EchoRequest"),
+            response: format!("{module}::EchoResponse"),
+            repeat_request: format!("{module}::EchoRepeatRequest"),
+            collect_response: format!("{module}::EchoCollectResponse"),
+        }
+    }
+
+    /// Uses `message_type` for every message.
+    fn all(message_type: &str) -> Self {
+        Self {
+            request: message_type.to_string(),
+            response: message_type.to_string(),
+            repeat_request: message_type.to_string(),
+            collect_response: message_type.to_string(),
+        }
+    }
+}
+
+/// Manually defines an `Echo` service in `package`, which has the same methods as `echopb.Echo`
+/// but uses the given message types and codec. Writes `<package>.Echo.rs` to `out_dir`.
+fn build_manual_echo_service(
+    package: &str,
+    message_types: &EchoMessageTypes,
+    codec_path: &str,
+    out_dir: &Path,
+) {
+    let echo_service = tonic_prost_build::manual::Service::builder()
+        .name("Echo")
+        .package(package)
+        .method(
+            tonic_prost_build::manual::Method::builder()
+                .name("echo")
+                .route_name("Echo")
+                .input_type(&message_types.request)
+                .output_type(&message_types.response)
+                .codec_path(codec_path)
+                .build(),
+        )
+        .method(
+            tonic_prost_build::manual::Method::builder()
+                .name("echo_bi_dir")
+                .route_name("EchoBiDir")
+                .input_type(&message_types.request)
+                .output_type(&message_types.response)
+                .codec_path(codec_path)
+                .client_streaming()
+                .server_streaming()
+                .build(),
+        )
+        .method(
+            tonic_prost_build::manual::Method::builder()
+                .name("echo_repeat")
+                .route_name("EchoRepeat")
+                .input_type(&message_types.repeat_request)
+                .output_type(&message_types.response)
+                .codec_path(codec_path)
+                .server_streaming()
+                .build(),
+        )
+        .method(
+            tonic_prost_build::manual::Method::builder()
+                .name("echo_collect")
+                .route_name("EchoCollect")
+                .input_type(&message_types.request)
+                .output_type(&message_types.collect_response)
+                .codec_path(codec_path)
+                .client_streaming()
+                .build(),
+        )
+        .build();
+
+    tonic_prost_build::manual::Builder::new()
+        .out_dir(out_dir)
+        .compile(&[echo_service]);
+}
@@ -0,0 +1,205 @@
+//! Decodes `google.protobuf.Any` messages, such as error details, by type URL.
+//!
+//! Register each message type once with [`AnyRegistry::register`], then
+//! [`AnyRegistry::decode`] turns an `Any` into a value that can be printed. Messages with
+//! unregistered type URLs are printed as hex.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fmt::Display;
+use std::fmt::Write;
+
+use prost::DecodeError;
+use prost::Message;
+use prost::Name;
+use prost_types::Any;
+use tonic_types::pb;
+
+use crate::echopb::Example1;
+use crate::echopb::Example2;
+
+type DecodeFn = fn(&[u8]) -> Result<Box<dyn Debug + Send + Sync>, DecodeError>;
+
+/// Maps type URLs to the message types to decode them as.
+#[derive(Debug, Clone, Default)]
+pub struct AnyRegistry {
+    decoders: HashMap<String, DecodeFn>,
+}
+
+impl AnyRegistry {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a registry with the standard `google.rpc` error details, and the example details
+    /// from `echo.proto`.
+    #[must_use]
+    pub fn with_error_details() -> Self {
+        let mut registry = Self::new();
+        registry.register::<Example1>();
+        registry.register::<Example2>();
+        // tonic-types' google.rpc messages do not implement Name
+        registry.register_type_url::<pb::BadRequest>(tonic_types::BadRequest::TYPE_URL);
+        registry.register_type_url::<pb::DebugInfo>(tonic_types::DebugInfo::TYPE_URL);
+        registry.register_type_url::<pb::ErrorInfo>(tonic_types::ErrorInfo::TYPE_URL);
+        registry.register_type_url::<pb::Help>(tonic_types::Help::TYPE_URL);
+        registry.register_type_url::<pb::LocalizedMessage>(tonic_types::LocalizedMessage::TYPE_URL);
+        registry.register_type_url::<pb::PreconditionFailure>(
+            tonic_types::PreconditionFailure::TYPE_URL,
+        );
+        registry.register_type_url::<pb::QuotaFailure>(tonic_types::QuotaFailure::TYPE_URL);
+        registry.register_type_url::<pb::RequestInfo>(tonic_types::RequestInfo::TYPE_URL);
+        registry.register_type_url::<pb::ResourceInfo>(tonic_types::ResourceInfo::TYPE_URL);
+        registry.register_type_url::<pb::RetryInfo>(tonic_types::RetryInfo::TYPE_URL);
+        registry
+    }
+
+    /// Decodes `Any` messages with the type URL of `M` as `M`.
+    pub fn register<M>(&mut self)
+    where
+        M: Name + Message + Default + Debug + Send + Sync + 'static,
+    {
+        self.register_type_url::<M>(&M::type_url());
+    }
+
+    /// Decodes `Any` messages with `type_url` as `M`, for messages that do not implement
+    /// [`Name`].
+    pub fn register_type_url<M>(&mut self, type_url: &str)
+    where
+        M: Message + Default + Debug + Send + Sync + 'static,
+    {
+        self.decoders.insert(type_url.to_string(), |value| {
+            Ok(Box::new(M::decode(value)?))
+        });
+    }
+
+    /// Decodes `any` with the type registered for its type URL.
+    #[must_use]
+    pub fn decode(&self, any: &Any) -> DecodedAny {
+        let type_url = any.type_url.clone();
+        let Some(decode) = self.decoders.get(&any.type_url) else {
+            return DecodedAny::Unknown {
+                type_url,
+                value: any.value.clone(),
+            };
+        };
+        match decode(&any.value) {
+            Ok(message) => DecodedAny::Message { type_url, message },
+            Err(error) => DecodedAny::Invalid {
+                type_url,
+                value: any.value.clone(),
+                error,
+            },
+        }
+    }
+}
+
+/// An `Any` message decoded by an [`AnyRegistry`].
+#[derive(Debug)]
+pub enum DecodedAny {
+    /// A message of a registered type.
+    Message {
+        type_url: String,
+        message: Box<dyn Debug + Send + Sync>,
+    },
+    /// A message with a type URL that is not registered.
+    Unknown { type_url: String, value: Vec<u8> },
+    /// A message that could not be decoded as its registered type.
+    Invalid {
+        type_url: String,
+        value: Vec<u8>,
+        error: DecodeError,
+    },
+}
+
+impl DecodedAny {
+    #[must_use]
+    pub fn type_url(&self) -> &str {
+        match self {
+            Self::Message { type_url, .. }
+            | Self::Unknown { type_url, .. }
+            | Self::Invalid { type_url, .. } => type_url,
+        }
+    }
+}
+
+impl Display for DecodedAny {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Message { type_url, message } => write!(f, "type={type_url} {message:?}"),
+            Self::Unknown { type_url, value } => {
+                write!(f, "type={type_url} unknown value=0x{}", hex(value))
+            }
+            Self::Invalid {
+                type_url,
+                value,
+                error,
+            } => write!(
+                f,
+                "type={type_url} invalid value=0x{} error={error}",
+                hex(value)
+            ),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let registry = AnyRegistry::with_error_details();
+
+        let any = Any::from_msg(&Example1 { int64_value: 99 }).unwrap();
+        let decoded = registry.decode(&any);
+        assert_eq!(decoded.type_url(), "type.googleapis.com/echopb.Example1");
+        assert_eq!(
+            decoded.to_string(),
+            "type=type.googleapis.com/echopb.Example1 Example1 { int64_value: 99 }"
+        );
+
+        let any = Any {
+            type_url: tonic_types::RetryInfo::TYPE_URL.to_string(),
+            value: pb::RetryInfo {
+                retry_delay: Some(prost_types::Duration {
+                    seconds: 2,
+                    nanos: 0,
+                }),
+            }
+            .encode_to_vec(),
+        };
+        assert!(matches!(registry.decode(&any), DecodedAny::Message { .. }));
+
+        let any = Any {
+            type_url: "type.googleapis.com/example.Unknown".to_string(),
+            value: vec![0x08, 0xff, 0x01],
+        };
+        assert_eq!(
+            registry.decode(&any).to_string(),
+            "type=type.googleapis.com/example.Unknown unknown value=0x08ff01"
+        );
+
+        // a varint that does not end
+        let any = Any {
+            type_url: Example1::type_url(),
+            value: vec![0x08, 0xff],
+        };
+        let decoded = registry.decode(&any);
+        assert!(matches!(decoded, DecodedAny::Invalid { .. }), "{decoded}");
+        assert!(
+            decoded.to_string().contains("invalid value=0x08ff"),
+            "{decoded}"
+        );
+    }
+}
@@ -0,0 +1,114 @@
+//! Measures how long streaming responses wait for space in their channel.
+//!
+//! Streaming RPCs send responses through a bounded channel to tonic, which only reads the next
+//! response when HTTP/2 flow control lets it send more data. When the client reads slowly, the
+//! channel fills and sends wait. [`TimedSender`] records that time, to study flow control with
+//! different channel capacities and HTTP/2 window sizes.
+
+use std::fmt::Display;
+use std::num::NonZeroUsize;
+use std::time::Duration;
+use std::time::Instant;
+
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::error::TrySendError;
+
+/// The default capacity of the response channel of each streaming RPC.
+pub const DEFAULT_STREAM_CHANNEL_CAPACITY: NonZeroUsize = NonZeroUsize::new(1).unwrap();
+
+/// Counts the sends on one stream, and how long they were blocked because the channel was full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SendStats {
+    pub sends: u64,
+    pub blocked_sends: u64,
+    pub blocked_time: Duration,
+    pub max_blocked_time: Duration,
+}
+
+impl Display for SendStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "sends={} blocked_sends={} blocked_time={:?} max_blocked_time={:?}",
+            self.sends, self.blocked_sends, self.blocked_time, self.max_blocked_time
+        )
+    }
+}
+
+/// An [`mpsc::Sender`] that records [`SendStats`].
+#[derive(Debug)]
+pub struct TimedSender<T> {
+    sender: mpsc::Sender<T>,
+    stats: SendStats,
+}
+
+impl<T> TimedSender<T> {
+    #[must_use]
+    pub fn new(sender: mpsc::Sender<T>) -> Self {
+        Self {
+            sender,
+            stats: SendStats::default(),
+        }
+    }
+
+    /// Sends `value`, waiting for space in the channel if it is full. Returns an error if the
+    /// receiver was dropped.
+    pub async fn send(&mut self, value: T) -> Result<(), SendError<T>> {
+        self.stats.sends += 1;
+        let value = match self.sender.try_send(value) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Closed(value)) => return Err(SendError(value)),
+            Err(TrySendError::Full(value)) => value,
+        };
+        let start = Instant::now();
+        let result = self.sender.send(value).await;
+        let blocked_time = start.elapsed();
+        self.stats.blocked_sends += 1;
+        self.stats.blocked_time += blocked_time;
+        self.stats.max_blocked_time = self.stats.max_blocked_time.max(blocked_time);
+        result
+    }
+
+    #[must_use]
+    pub const fn stats(&self) -> SendStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_timed_sender() {
+        let (sender, mut receiver) = mpsc::channel(1);
+        let mut sender = TimedSender::new(sender);
+        sender.send(1).await.unwrap();
+        assert_eq!(
+            sender.stats(),
+            SendStats {
+                sends: 1,
+                ..SendStats::default()
+            }
+        );
+
+        // the channel is full until the receiver reads
+        let reader = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let first = receiver.recv().await;
+            (first, receiver)
+        });
+        sender.send(2).await.unwrap();
+        let stats = sender.stats();
+        assert_eq!(stats.sends, 2);
+        assert_eq!(stats.blocked_sends, 1);
+        assert!(stats.blocked_time >= Duration::from_millis(10), "{stats}");
+        assert_eq!(stats.max_blocked_time, stats.blocked_time);
+
+        let (first, receiver) = reader.await.unwrap();
+        assert_eq!(first, Some(1));
+        drop(receiver);
+        assert_eq!(sender.send(3).await, Err(SendError(3)));
+    }
+}
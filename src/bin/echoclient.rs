@@ -1,30 +1,180 @@
+use std::process::ExitCode;
+use std::time::SystemTime;
+
 use clap::Parser;
-use prost::Message;
-use prost::Name;
 use rustgrpcdemo::{
-    echopb::{EchoRequest, Example1, Example2, echo_client::EchoClient},
-    now_formatted,
+    ClientTlsArgs,
+    any_registry::AnyRegistry,
+    compression::{Compression, echo_client},
+    connect,
+    echo_metadata::{ECHO_METADATA_HEADER, PrintMetadata},
+    echopb::{EchoRequest, EchoResponse},
+    json_wkt, now_formatted,
+    rich_error::decode_details,
+    unix_nanos_now,
 };
-
-/// Returns the details from a gRPC grpc-status-details-bin response header.
-/// If there is an error it return an empty Vec.
-/// See: <https://github.com/grpc/grpc/blob/master/doc/PROTOCOL-HTTP2.md>
-fn decode_details(details: &[u8]) -> Vec<prost_types::Any> {
-    let Ok(details_status) = tonic_types::Status::decode(details) else {
-        return vec![];
-    };
-    details_status.details
-}
+use tonic::transport::Channel;
+use tonic_health::pb::HealthCheckRequest;
+use tonic_health::pb::health_check_response::ServingStatus;
+use tonic_health::pb::health_client::HealthClient;
+use tower::util::Either;
 
 #[derive(Debug, Parser)]
 struct Args {
-    // The gRPC URL to connect to.
+    // The gRPC URL to connect to. Use unix:///path to connect to a Unix domain socket.
     #[clap(long, default_value = "http://localhost:8001/")]
     grpc_url: String,
+
+    #[command(flatten)]
+    tls: ClientTlsArgs,
+
+    /// Compression for requests. The client accepts responses with any supported compression.
+    #[clap(long, value_enum, default_value_t = Compression::None)]
+    compression: Compression,
+
+    /// Print the response headers and trailers.
+    #[clap(long, default_value_t = false)]
+    print_metadata: bool,
+
+    /// Ask the server to echo this request metadata in the response headers and trailers: `*`
+    /// for all of it, or a comma-separated list of keys.
+    #[clap(long)]
+    echo_metadata: Option<String>,
+
+    /// Send a payload of this many bytes.
+    #[clap(long, default_value_t = 0)]
+    payload_size: usize,
+
+    /// Ask the server to pad or truncate the response payload to this many bytes.
+    #[clap(long)]
+    response_size: Option<u32>,
+
+    /// Ask the server to wait this long before responding, as a protobuf JSON duration like
+    /// `1.5s`.
+    #[clap(long)]
+    delay: Option<prost_types::Duration>,
+
+    /// Attach an attribute to the request, which the server echoes. The value is parsed as JSON
+    /// if it is valid, and used as a string otherwise. Can be repeated.
+    #[clap(long = "attribute", value_name = "KEY=VALUE", value_parser = parse_attribute)]
+    attributes: Vec<(String, serde_json::Value)>,
+
+    /// Check the server's health with grpc.health.v1.Health/Check instead of calling Echo. Exits
+    /// with an error if the service is not `SERVING`.
+    #[clap(long, default_value_t = false)]
+    health: bool,
+
+    /// Watch the server's health with grpc.health.v1.Health/Watch until the service is not
+    /// `SERVING`, then exit with an error.
+    #[clap(long, default_value_t = false, conflicts_with = "health")]
+    health_watch: bool,
+
+    /// The service to check with `--health` or `--health-watch`. The default empty name checks
+    /// the whole server.
+    #[clap(long, default_value = "")]
+    health_service: String,
+}
+
+fn parse_attribute(attribute: &str) -> Result<(String, serde_json::Value), String> {
+    let (key, value) = attribute
+        .split_once('=')
+        .ok_or_else(|| format!("attribute {attribute:?} must be KEY=VALUE"))?;
+    let value = serde_json::from_str(value)
+        .unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+    Ok((key.to_string(), value))
+}
+
+/// Prints `response`, with the round trip time, and the time the server took.
+fn print_response(response: &EchoResponse) {
+    let round_trip_micros = unix_nanos_now().saturating_sub(response.send_time_unix_nanos) / 1000;
+    println!(
+        "{} received response.output={} payload_len={} sequence={} round_trip_us={round_trip_micros}",
+        now_formatted(),
+        response.output,
+        response.payload.len(),
+        response.sequence
+    );
+    if let (Some(receive_time), Some(send_time)) =
+        (response.server_receive_time, response.server_send_time)
+    {
+        // the server sets these, so they may be invalid or out of order
+        let server_micros = SystemTime::try_from(send_time)
+            .ok()
+            .zip(SystemTime::try_from(receive_time).ok())
+            .and_then(|(send_time, receive_time)| send_time.duration_since(receive_time).ok())
+            .map_or_else(
+                || "unknown".to_string(),
+                |duration| duration.as_micros().to_string(),
+            );
+        println!(
+            "  server_receive_time={receive_time} server_send_time={send_time} \
+             server_us={server_micros}"
+        );
+    }
+    if let Some(attributes) = &response.attributes {
+        println!(
+            "  attributes={}",
+            serde_json::Value::Object(json_wkt::to_json(attributes))
+        );
+    }
+}
+
+/// Checks the health of `service` with a single Check call.
+async fn check_health(
+    channel: Channel,
+    service: String,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let mut client = HealthClient::new(channel);
+    let response = client
+        .check(HealthCheckRequest {
+            service: service.clone(),
+        })
+        .await?
+        .into_inner();
+    println!(
+        "{} health service={service:?} status={}",
+        now_formatted(),
+        response.status().as_str_name()
+    );
+    Ok(exit_code_for_status(response.status()))
+}
+
+/// Watches the health of `service`, returning when it is no longer `SERVING`.
+async fn watch_health(
+    channel: Channel,
+    service: String,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let mut client = HealthClient::new(channel);
+    let mut response_stream = client
+        .watch(HealthCheckRequest {
+            service: service.clone(),
+        })
+        .await?
+        .into_inner();
+    while let Some(response) = response_stream.message().await? {
+        println!(
+            "{} health watch service={service:?} status={}",
+            now_formatted(),
+            response.status().as_str_name()
+        );
+        if response.status() != ServingStatus::Serving {
+            return Ok(exit_code_for_status(response.status()));
+        }
+    }
+    println!("{} health watch stream ended", now_formatted());
+    Ok(ExitCode::FAILURE)
+}
+
+fn exit_code_for_status(status: ServingStatus) -> ExitCode {
+    if status == ServingStatus::Serving {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
     let args = Args::parse();
 
     println!(
@@ -32,22 +182,46 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         args.grpc_url,
         now_formatted()
     );
-    let mut client = EchoClient::connect(args.grpc_url).await?;
+    let channel = connect(&args.grpc_url, args.tls.tls_config()?).await?;
+    if args.health {
+        return check_health(channel, args.health_service).await;
+    }
+    if args.health_watch {
+        return watch_health(channel, args.health_service).await;
+    }
 
-    let request = EchoRequest {
-        input: "Hello, world!".to_string(),
+    let channel = if args.print_metadata {
+        Either::Left(PrintMetadata::new(channel))
+    } else {
+        Either::Right(channel)
     };
+    let mut client = echo_client(channel, args.compression);
+
+    let mut request = tonic::Request::new(EchoRequest {
+        input: "Hello, world!".to_string(),
+        payload: vec![b'x'; args.payload_size],
+        response_size: args.response_size,
+        send_time_unix_nanos: unix_nanos_now(),
+        sequence: 1,
+        delay: args.delay,
+        attributes: (!args.attributes.is_empty())
+            .then(|| json_wkt::from_json(args.attributes.into_iter().collect())),
+    });
+    if let Some(echo_metadata) = &args.echo_metadata {
+        request
+            .metadata_mut()
+            .insert(ECHO_METADATA_HEADER, echo_metadata.parse()?);
+    }
     match client.echo(request).await {
-        Ok(response) => {
-            let response = response.into_inner();
-            println!(
-                "{} received response.output={}",
-                now_formatted(),
-                response.output
-            );
-        }
+        Ok(response) => print_response(response.get_ref()),
         Err(grpc_status) => {
-            let details = decode_details(grpc_status.details());
+            let details = match decode_details(&grpc_status) {
+                Ok(details) => details,
+                Err(err) => {
+                    eprintln!("{} failed decoding error details: {err}", now_formatted());
+                    vec![]
+                }
+            };
             println!(
                 "{} code:{} {:?} details_len={} msg={}",
                 now_formatted(),
@@ -56,49 +230,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 details.len(),
                 grpc_status.message()
             );
+            let registry = AnyRegistry::with_error_details();
             for (i, detail) in details.iter().enumerate() {
-                println!(
-                    "  details i={i} type={} len={}",
-                    detail.type_url,
-                    detail.value.len()
-                );
-                let value_bytes: &[u8] = &detail.value;
-                if detail.type_url == Example1::type_url() {
-                    let example1 = Example1::decode(value_bytes).unwrap();
-                    println!("    details i={i} {example1:?}");
-                } else if detail.type_url == Example2::type_url() {
-                    let example2 = Example2::decode(value_bytes).unwrap();
-                    println!("    details i={i} {example2:?}");
-                }
+                println!("  details i={i} {}", registry.decode(detail));
             }
         }
     }
 
-    Ok(())
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_decode_details() {
-        // From a Go server
-        const DETAILS_BYTES_HEX: &[u8] = &[
-            0x08, 0x0D, 0x12, 0x14, 0x65, 0x72, 0x72, 0x6F, 0x72, 0x20, 0x77, 0x69, 0x74, 0x68,
-            0x20, 0x32, 0x20, 0x64, 0x65, 0x74, 0x61, 0x69, 0x6C, 0x73, 0x1A, 0x29, 0x0A, 0x23,
-            0x74, 0x79, 0x70, 0x65, 0x2E, 0x67, 0x6F, 0x6F, 0x67, 0x6C, 0x65, 0x61, 0x70, 0x69,
-            0x73, 0x2E, 0x63, 0x6F, 0x6D, 0x2F, 0x65, 0x63, 0x68, 0x6F, 0x70, 0x62, 0x2E, 0x45,
-            0x78, 0x61, 0x6D, 0x70, 0x6C, 0x65, 0x31, 0x12, 0x02, 0x08, 0x63, 0x1A, 0x30, 0x0A,
-            0x23, 0x74, 0x79, 0x70, 0x65, 0x2E, 0x67, 0x6F, 0x6F, 0x67, 0x6C, 0x65, 0x61, 0x70,
-            0x69, 0x73, 0x2E, 0x63, 0x6F, 0x6D, 0x2F, 0x65, 0x63, 0x68, 0x6F, 0x70, 0x62, 0x2E,
-            0x45, 0x78, 0x61, 0x6D, 0x70, 0x6C, 0x65, 0x32, 0x12, 0x09, 0x09, 0x1F, 0x85, 0xEB,
-            0x51, 0xB8, 0x1E, 0x09, 0x40,
-        ];
-
-        let result = decode_details(DETAILS_BYTES_HEX);
-        assert_eq!(2, result.len());
-        assert_eq!(result[0].type_url, Example1::type_url());
-        assert_eq!(result[1].type_url, Example2::type_url());
-    }
+    Ok(ExitCode::SUCCESS)
 }
@@ -1,9 +1,13 @@
 use std::{pin::Pin, task::Poll, time::Duration};
 
 use async_stream::stream;
+use clap::Parser;
 use rustgrpcdemo::{
-    echopb::{EchoRequest, echo_client::EchoClient},
-    now_formatted,
+    ClientTlsArgs,
+    compression::{Compression, CountBytes, echo_client},
+    connect,
+    echopb::{EchoRepeatRequest, EchoRequest, EchoResponse, echo_client::EchoClient},
+    now_formatted, unix_nanos_now,
 };
 use tokio::time::Sleep;
 use tokio_stream::Stream;
@@ -117,6 +121,9 @@ impl Stream for RawRequestStream {
 
         let request = EchoRequest {
             input: format!("message {}", self_mut.messages_sent),
+            send_time_unix_nanos: unix_nanos_now(),
+            sequence: self_mut.messages_sent as u64,
+            ..EchoRequest::default()
         };
         println!(
             "{}     RawRequestStream: returning Ready(Some(request.input={})",
@@ -183,11 +190,140 @@ impl Future for GetAllFuture {
     }
 }
 
+const NUM_MESSAGES: usize = 10;
+const MESSAGE_SLEEP: Duration = Duration::from_millis(500);
+
+type Client = EchoClient<CountBytes<tonic::transport::Channel>>;
+
+/// The streaming RPC to demonstrate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Mode {
+    /// Bidirectional streaming with `EchoBiDir`.
+    Bidi,
+    /// Server streaming with `EchoRepeat`.
+    Repeat,
+    /// Client streaming with `EchoCollect`.
+    Collect,
+}
+
+#[derive(Debug, Parser)]
+struct Args {
+    /// The gRPC URL to connect to. Use `unix:///path` to connect to a Unix domain socket.
+    #[clap(long, default_value = "http://[::1]:8001/")]
+    grpc_url: String,
+
+    #[command(flatten)]
+    tls: ClientTlsArgs,
+
+    /// Compression for requests. The client accepts responses with any supported compression.
+    #[clap(long, value_enum, default_value_t = Compression::None)]
+    compression: Compression,
+
+    /// The streaming RPC to call.
+    #[clap(long, value_enum, default_value_t = Mode::Bidi)]
+    mode: Mode,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    const GRPC_URL: &str = "http://[::1]:8001/";
-    const NUM_MESSAGES: usize = 10;
-    const MESSAGE_SLEEP: Duration = Duration::from_millis(500);
+    let args = Args::parse();
+    println!(
+        "{} stream client connecting to GRPC_URL={} ...",
+        now_formatted(),
+        args.grpc_url
+    );
+    let channel = connect(&args.grpc_url, args.tls.tls_config()?).await?;
+    let mut client = echo_client(channel, args.compression);
+
+    match args.mode {
+        Mode::Bidi => bidi(&mut client).await,
+        Mode::Repeat => repeat(&mut client).await,
+        Mode::Collect => collect(&mut client).await,
+    }
+}
+
+/// Calls `EchoRepeat` and prints each response as it arrives.
+async fn repeat(client: &mut Client) -> Result<(), Box<dyn std::error::Error>> {
+    let request = EchoRepeatRequest {
+        input: "repeat me".to_string(),
+        count: u32::try_from(NUM_MESSAGES)?,
+        interval_ms: u32::try_from(MESSAGE_SLEEP.as_millis())?,
+    };
+    println!(
+        "{} calling client.echo_repeat count={} interval_ms={} ...",
+        now_formatted(),
+        request.count,
+        request.interval_ms
+    );
+    let response_stream = client.echo_repeat(request).await?.into_inner();
+    receive_responses(response_stream).await?;
+    Ok(())
+}
+
+/// Prints each response on `response_stream` as it arrives, with the round-trip latency of
+/// responses to requests that set `send_time_unix_nanos`. Warns if the sequence numbers go
+/// backwards.
+async fn receive_responses(
+    mut response_stream: tonic::Streaming<EchoResponse>,
+) -> Result<(), tonic::Status> {
+    let mut received_messages = 0;
+    let mut last_sequence = 0;
+    while let Some(response) = response_stream.message().await? {
+        let round_trip = if response.send_time_unix_nanos == 0 {
+            String::new()
+        } else {
+            let round_trip_micros =
+                unix_nanos_now().saturating_sub(response.send_time_unix_nanos) / 1000;
+            format!(" round_trip_us={round_trip_micros}")
+        };
+        println!(
+            "{} received response.output={} sequence={}{round_trip}",
+            now_formatted(),
+            response.output,
+            response.sequence
+        );
+        if response.sequence != 0 {
+            if response.sequence <= last_sequence {
+                println!(
+                    "{}     WARNING: out of order: sequence={} after sequence={last_sequence}",
+                    now_formatted(),
+                    response.sequence
+                );
+            }
+            last_sequence = response.sequence;
+        }
+        received_messages += 1;
+    }
+    println!(
+        "{} stream complete received_messages={received_messages}",
+        now_formatted()
+    );
+    Ok(())
+}
+
+/// Calls `EchoCollect` with [`RawRequestStream`] and prints the single response.
+async fn collect(client: &mut Client) -> Result<(), Box<dyn std::error::Error>> {
+    println!(
+        "{} calling client.echo_collect using RawRequestStream ...",
+        now_formatted()
+    );
+    let request_stream = RawRequestStream::new(NUM_MESSAGES, MESSAGE_SLEEP);
+    let response = client
+        .echo_collect(tonic::Request::new(request_stream))
+        .await?
+        .into_inner();
+    println!(
+        "{} received response.count={} response.output={}",
+        now_formatted(),
+        response.count,
+        response.output
+    );
+    Ok(())
+}
+
+/// Demonstrates futures and streams, then calls `EchoBiDir` with [`RawRequestStream`] and with
+/// async-stream.
+async fn bidi(client: &mut Client) -> Result<(), Box<dyn std::error::Error>> {
     const FUTURE_EXAMPLE_SLEEP: Duration = Duration::from_millis(100);
 
     // example of a raw Future that wraps a tokio sleep
@@ -206,36 +342,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         result.len()
     );
     println!();
-
-    println!(
-        "{} stream client connecting to GRPC_URL={GRPC_URL} ...",
-        now_formatted()
-    );
-    let mut client = EchoClient::connect(GRPC_URL).await?;
-
     println!(
         "{} starting stream using RawRequestStream ...",
         now_formatted()
     );
     let request_stream = RawRequestStream::new(NUM_MESSAGES, MESSAGE_SLEEP);
 
-    let mut response_stream = client
+    let response_stream = client
         .echo_bi_dir(tonic::Request::new(request_stream))
         .await?
         .into_inner();
-    let mut received_messages = 0;
-    while let Some(response) = response_stream.message().await? {
-        println!(
-            "{} received response.output={}",
-            now_formatted(),
-            response.output
-        );
-        received_messages += 1;
-    }
-    println!(
-        "{} stream complete received_messages={received_messages}",
-        now_formatted()
-    );
+    receive_responses(response_stream).await?;
     println!();
 
     // repeat stream using async-stream
@@ -243,6 +360,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         for i in 0..NUM_MESSAGES {
             let request = EchoRequest {
                 input: format!("message {i}"),
+                send_time_unix_nanos: unix_nanos_now(),
+                sequence: i as u64 + 1,
+                ..EchoRequest::default()
             };
             println!(
                 "{}     async-stream: yielding request.input={} ...",
@@ -265,23 +385,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         "{} calling client.echo_bi_dir using async-stream ...",
         now_formatted()
     );
-    let mut response_stream = client
+    let response_stream = client
         .echo_bi_dir(tonic::Request::new(request_stream))
         .await?
         .into_inner();
-    let mut received_messages = 0;
-    while let Some(response) = response_stream.message().await? {
-        println!(
-            "{} received response.output={}",
-            now_formatted(),
-            response.output
-        );
-        received_messages += 1;
-    }
-    println!(
-        "{} stream complete received_messages={received_messages}",
-        now_formatted()
-    );
+    receive_responses(response_stream).await?;
 
     Ok(())
 }
@@ -0,0 +1,286 @@
+//! Broadcasts `EchoBiDir` messages to every stream in a chat room.
+//!
+//! Streams that set the `x-echo-room` request header join the named room instead of echoing:
+//! each message is sent to every member, including the sender, with the sender's identity and
+//! the time the server received it. Each member has a bounded buffer. A member that reads too
+//! slowly skips the oldest messages, and is sent a notification with the number it missed.
+
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::PoisonError;
+use std::time::SystemTime;
+
+use prost_types::Struct;
+use prost_types::Timestamp;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tonic::Status;
+use tonic::metadata::MetadataMap;
+
+use crate::echopb::EchoRequest;
+use crate::echopb::EchoResponse;
+
+/// The name of the room for an `EchoBiDir` stream to join.
+pub const ROOM_HEADER: &str = "x-echo-room";
+
+/// The default number of messages buffered for each member of a room.
+pub const DEFAULT_ROOM_BUFFER: NonZeroUsize = NonZeroUsize::new(16).unwrap();
+
+/// Returns the room selected by the `x-echo-room` header, or `None` if it is not set.
+pub fn room_name(metadata: &MetadataMap) -> Result<Option<String>, Status> {
+    let Some(value) = metadata.get(ROOM_HEADER) else {
+        return Ok(None);
+    };
+    let room = value
+        .to_str()
+        .map_err(|err| Status::invalid_argument(format!("invalid {ROOM_HEADER} header: {err}")))?
+        .trim();
+    if room.is_empty() {
+        return Err(Status::invalid_argument(format!(
+            "{ROOM_HEADER} header must not be empty"
+        )));
+    }
+    Ok(Some(room.to_string()))
+}
+
+/// A message sent to a room.
+#[derive(Debug, Clone)]
+pub struct RoomMessage {
+    pub sender: String,
+    pub input: String,
+    pub sequence: u64,
+    pub receive_time: Timestamp,
+}
+
+/// What a member of a room receives.
+#[derive(Debug, Clone)]
+pub enum RoomEvent {
+    Message(RoomMessage),
+    /// The member's buffer was full, so it skipped this many messages.
+    Lagged(u64),
+}
+
+impl RoomEvent {
+    /// Returns the response that sends this event to a member of `room`. The attributes
+    /// describe the event, so clients do not need to parse the output.
+    #[must_use]
+    pub fn response(&self, room: &str) -> EchoResponse {
+        let mut attributes = Struct {
+            fields: [("room".to_string(), room.into())].into(),
+        };
+        let mut response = match self {
+            Self::Message(message) => {
+                attributes
+                    .fields
+                    .insert("event".to_string(), "message".into());
+                attributes
+                    .fields
+                    .insert("sender".to_string(), message.sender.as_str().into());
+                EchoResponse {
+                    output: format!("{}: {}", message.sender, message.input),
+                    sequence: message.sequence,
+                    server_receive_time: Some(message.receive_time),
+                    ..EchoResponse::default()
+                }
+            }
+            Self::Lagged(skipped) => {
+                attributes
+                    .fields
+                    .insert("event".to_string(), "lagged".into());
+                attributes
+                    .fields
+                    .insert("skipped".to_string(), skipped_count(*skipped).into());
+                EchoResponse {
+                    output: format!("lagged: skipped {skipped} messages"),
+                    ..EchoResponse::default()
+                }
+            }
+        };
+        response.attributes = Some(attributes);
+        response.server_send_time = Some(SystemTime::now().into());
+        response
+    }
+}
+
+/// Protobuf `Value` numbers are `f64`.
+fn skipped_count(skipped: u64) -> f64 {
+    f64::from(u32::try_from(skipped).unwrap_or(u32::MAX))
+}
+
+/// The open rooms, which are created when the first member joins and removed when the last
+/// member leaves.
+#[derive(Debug)]
+pub struct ChatRooms {
+    buffer: NonZeroUsize,
+    rooms: Mutex<HashMap<String, Room>>,
+}
+
+/// An open room. Its members are counted under the rooms lock: the channel's receiver count only
+/// drops after a leaving member has released the lock, so two members leaving together could
+/// each still count the other.
+#[derive(Debug)]
+struct Room {
+    sender: broadcast::Sender<RoomMessage>,
+    members: usize,
+}
+
+impl ChatRooms {
+    /// Returns rooms that buffer up to `buffer` messages for each member.
+    #[must_use]
+    pub fn new(buffer: NonZeroUsize) -> Self {
+        Self {
+            buffer,
+            rooms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds `member` to `room`, creating it if needed. The member leaves when the returned
+    /// [`RoomMembership`] is dropped.
+    #[must_use]
+    pub fn join(self: &Arc<Self>, room: &str, member: String) -> RoomMembership {
+        // subscribe while holding the lock: otherwise the last member could leave in between and
+        // remove the room, and this member would be alone on a channel nobody else can join
+        let mut rooms = self.lock_rooms();
+        let open_room = rooms.entry(room.to_string()).or_insert_with(|| Room {
+            sender: broadcast::channel(self.buffer.get()).0,
+            members: 0,
+        });
+        open_room.members += 1;
+        let members = open_room.members;
+        let (sender, receiver) = (open_room.sender.clone(), open_room.sender.subscribe());
+        drop(rooms);
+        println!("room {room:?}: {member} joined; members={members}");
+        RoomMembership {
+            rooms: Arc::clone(self),
+            room: room.to_string(),
+            member,
+            sender,
+            receiver,
+        }
+    }
+
+    /// Returns the number of members of `room`.
+    #[must_use]
+    pub fn member_count(&self, room: &str) -> usize {
+        self.lock_rooms().get(room).map_or(0, |room| room.members)
+    }
+
+    fn lock_rooms(&self) -> MutexGuard<'_, HashMap<String, Room>> {
+        self.rooms.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A member of a room, which leaves the room when dropped.
+#[derive(Debug)]
+pub struct RoomMembership {
+    rooms: Arc<ChatRooms>,
+    room: String,
+    member: String,
+    sender: broadcast::Sender<RoomMessage>,
+    receiver: broadcast::Receiver<RoomMessage>,
+}
+
+impl RoomMembership {
+    #[must_use]
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+
+    /// Sends `request` to every member of the room, including this one.
+    pub fn publish(&self, request: &EchoRequest) {
+        let message = RoomMessage {
+            sender: self.member.clone(),
+            input: request.input.clone(),
+            sequence: request.sequence,
+            receive_time: SystemTime::now().into(),
+        };
+        // fails only if there are no members, but this member is one
+        let _ = self.sender.send(message);
+    }
+
+    /// Waits for the next event in the room. Cancel safe, so it can be used in `select!`.
+    pub async fn next(&mut self) -> RoomEvent {
+        loop {
+            match self.receiver.recv().await {
+                Ok(message) => return RoomEvent::Message(message),
+                Err(RecvError::Lagged(skipped)) => return RoomEvent::Lagged(skipped),
+                // the room cannot close while this membership holds a sender
+                Err(RecvError::Closed) => {}
+            }
+        }
+    }
+}
+
+impl Drop for RoomMembership {
+    fn drop(&mut self) {
+        let mut rooms = self.rooms.lock_rooms();
+        let Some(room) = rooms.get_mut(&self.room) else {
+            return;
+        };
+        room.members -= 1;
+        let members = room.members;
+        println!(
+            "room {:?}: {} left; members={members}",
+            self.room, self.member
+        );
+        if members == 0 {
+            rooms.remove(&self.room);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(input: &str) -> EchoRequest {
+        EchoRequest {
+            input: input.to_string(),
+            ..EchoRequest::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_and_lag() {
+        let rooms = Arc::new(ChatRooms::new(NonZeroUsize::new(2).unwrap()));
+        let mut alice = rooms.join("lobby", "alice".to_string());
+        let mut bob = rooms.join("lobby", "bob".to_string());
+        let _carol = rooms.join("other", "carol".to_string());
+        assert_eq!(rooms.member_count("lobby"), 2);
+
+        alice.publish(&request("hello"));
+        for member in [&mut alice, &mut bob] {
+            let response = member.next().await.response("lobby");
+            assert_eq!(response.output, "alice: hello");
+            let attributes = response.attributes.unwrap().fields;
+            assert_eq!(attributes["sender"], "alice".into());
+            assert_eq!(attributes["room"], "lobby".into());
+        }
+
+        // bob sends more than the buffer holds without reading, while alice reads each message
+        for input in ["1", "2", "3", "4"] {
+            bob.publish(&request(input));
+            assert!(matches!(alice.next().await, RoomEvent::Message(_)));
+        }
+        let response = bob.next().await.response("lobby");
+        assert_eq!(response.output, "lagged: skipped 2 messages");
+        assert_eq!(bob.next().await.response("lobby").output, "bob: 3");
+
+        drop(alice);
+        assert_eq!(rooms.member_count("lobby"), 1);
+        drop(bob);
+        assert_eq!(rooms.member_count("lobby"), 0);
+        assert!(!rooms.lock_rooms().contains_key("lobby"));
+        assert_eq!(rooms.member_count("other"), 1);
+
+        assert_eq!(room_name(&MetadataMap::new()).unwrap(), None);
+        let mut metadata = MetadataMap::new();
+        metadata.insert(ROOM_HEADER, " lobby ".parse().unwrap());
+        assert_eq!(room_name(&metadata).unwrap().unwrap(), "lobby");
+        metadata.insert(ROOM_HEADER, "".parse().unwrap());
+        assert!(room_name(&metadata).is_err());
+    }
+}
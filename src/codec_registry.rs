@@ -0,0 +1,161 @@
+//! Chooses the codec for each request from the subtype of its `content-type`.
+//!
+//! Tonic's generated servers always use the codec they were generated with. [`CodecRegistry`]
+//! serves several generated copies of the same gRPC service, each with a different
+//! [`tonic::codec::Codec`], and sends each request to the copy for its content type. This lets
+//! one port serve clients that use protobuf and JSON.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::task::Context;
+use std::task::Poll;
+
+use tonic::Status;
+use tonic::body::Body;
+use tonic::codegen::BoxFuture;
+use tonic::codegen::Service;
+use tonic::codegen::http;
+use tonic::server::NamedService;
+use tower::ServiceExt;
+use tower::util::BoxCloneSyncService;
+
+/// The content type for gRPC requests. The default subtype is `proto`.
+pub const GRPC_CONTENT_TYPE: &str = "application/grpc";
+
+type CodecService = BoxCloneSyncService<http::Request<Body>, http::Response<Body>, Infallible>;
+
+/// Serves a gRPC service with the codec registered for the `content-type` subtype of each
+/// request.
+///
+/// Requests for `application/grpc` and `application/grpc+proto` use the protobuf service.
+/// Requests for an unregistered subtype fail with `UNIMPLEMENTED`, and requests that are not
+/// `application/grpc` fail with HTTP 415 Unsupported Media Type. Responses have the same
+/// `content-type` as the request.
+#[derive(Debug, Clone)]
+pub struct CodecRegistry<S> {
+    proto_service: S,
+    codec_services: HashMap<String, CodecService>,
+}
+
+impl<S: NamedService> CodecRegistry<S> {
+    /// Returns a registry that serves `proto_service` for protobuf requests.
+    #[must_use]
+    pub fn new(proto_service: S) -> Self {
+        Self {
+            proto_service,
+            codec_services: HashMap::new(),
+        }
+    }
+
+    /// Serves `service` for requests with `content-type: application/grpc+{subtype}`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `service` is not a copy of the protobuf service with the same name.
+    #[must_use]
+    pub fn register<T>(mut self, subtype: &str, service: T) -> Self
+    where
+        T: Service<http::Request<Body>, Response = http::Response<Body>, Error = Infallible>
+            + NamedService
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        T::Future: Send + 'static,
+    {
+        assert_eq!(
+            T::NAME,
+            S::NAME,
+            "codec service for subtype {subtype:?} must have the same name"
+        );
+        self.codec_services
+            .insert(subtype.to_string(), BoxCloneSyncService::new(service));
+        self
+    }
+}
+
+impl<S: NamedService> NamedService for CodecRegistry<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S> Service<http::Request<Body>> for CodecRegistry<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<Body>, Error = Infallible>
+        + NamedService
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = http::Response<Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // each request is sent to a clone of the selected service, which is polled by oneshot
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        let Some(content_type) = request.headers().get(http::header::CONTENT_TYPE).cloned() else {
+            return Box::pin(std::future::ready(Ok(unsupported_media_type())));
+        };
+        let subtype = content_type.to_str().ok().and_then(grpc_content_subtype);
+        let response_future: BoxFuture<Self::Response, Self::Error> = match subtype {
+            None => return Box::pin(std::future::ready(Ok(unsupported_media_type()))),
+            Some("" | "proto") => Box::pin(self.proto_service.clone().oneshot(request)),
+            Some(subtype) => {
+                let Some(codec_service) = self.codec_services.get(subtype) else {
+                    let status = Status::unimplemented(format!(
+                        "unsupported content-type {content_type:?} for {}",
+                        S::NAME
+                    ));
+                    return Box::pin(std::future::ready(Ok(status.into_http())));
+                };
+                Box::pin(codec_service.clone().oneshot(request))
+            }
+        };
+
+        Box::pin(async move {
+            let mut response = response_future.await?;
+            response
+                .headers_mut()
+                .insert(http::header::CONTENT_TYPE, content_type);
+            Ok(response)
+        })
+    }
+}
+
+/// Returns the subtype of a gRPC content type: `""` for `application/grpc`, or `json` for
+/// `application/grpc+json`. Returns `None` if `content_type` is not a gRPC content type.
+fn grpc_content_subtype(content_type: &str) -> Option<&str> {
+    let rest = content_type.strip_prefix(GRPC_CONTENT_TYPE)?;
+    if rest.is_empty() {
+        Some(rest)
+    } else {
+        rest.strip_prefix('+')
+    }
+}
+
+fn unsupported_media_type() -> http::Response<Body> {
+    let mut response = http::Response::new(Body::empty());
+    *response.status_mut() = http::StatusCode::UNSUPPORTED_MEDIA_TYPE;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grpc_content_subtype() {
+        assert_eq!(grpc_content_subtype("application/grpc"), Some(""));
+        assert_eq!(
+            grpc_content_subtype("application/grpc+proto"),
+            Some("proto")
+        );
+        assert_eq!(grpc_content_subtype("application/grpc+json"), Some("json"));
+        assert_eq!(grpc_content_subtype("application/grpc-web"), None);
+        assert_eq!(grpc_content_subtype("application/json"), None);
+    }
+}
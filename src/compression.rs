@@ -0,0 +1,337 @@
+//! Message compression, and counting the compressed and uncompressed bytes of each RPC.
+//!
+//! [`CountBytes`] wraps a client channel or a server, parses the gRPC messages in the request and
+//! response bodies, and prints the bytes sent on the wire and the bytes after decompressing for
+//! each RPC. This shows how much compression saves for a workload.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::task::Context;
+use std::task::Poll;
+
+use bytes::Bytes;
+use bytes::BytesMut;
+use http_body::Frame;
+use http_body::SizeHint;
+use tonic::Status;
+use tonic::body::Body;
+use tonic::codec::CompressionEncoding;
+use tonic::codegen::BoxFuture;
+use tonic::codegen::Service;
+use tonic::codegen::StdError;
+use tonic::codegen::http;
+use tonic::server::NamedService;
+
+use crate::echopb::echo_client::EchoClient;
+use crate::now_formatted;
+
+/// The encodings that servers and clients accept from their peers.
+pub const ACCEPTED_ENCODINGS: [CompressionEncoding; 2] =
+    [CompressionEncoding::Gzip, CompressionEncoding::Zstd];
+
+const ENCODING_HEADER: &str = "grpc-encoding";
+
+/// The gRPC length-prefixed message header: a compressed flag and a 4 byte length.
+const MESSAGE_HEADER_LEN: usize = 5;
+
+/// The compression for sent messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Returns the encoding to pass to `send_compressed`, or `None` to send uncompressed messages.
+    #[must_use]
+    pub const fn encoding(self) -> Option<CompressionEncoding> {
+        match self {
+            Self::None => None,
+            Self::Gzip => Some(CompressionEncoding::Gzip),
+            Self::Zstd => Some(CompressionEncoding::Zstd),
+        }
+    }
+
+    /// Returns the compression in the `grpc-encoding` header of a request or response.
+    fn from_headers(headers: &http::HeaderMap) -> Self {
+        match headers
+            .get(ENCODING_HEADER)
+            .map(http::HeaderValue::as_bytes)
+        {
+            Some(b"gzip") => Self::Gzip,
+            Some(b"zstd") => Self::Zstd,
+            _ => Self::None,
+        }
+    }
+}
+
+/// Returns an `EchoClient` that compresses requests with `compression`, accepts compressed
+/// responses, and prints the bytes of each RPC. `channel` is usually a
+/// [`Channel`](tonic::transport::Channel).
+#[must_use]
+pub fn echo_client<S>(channel: S, compression: Compression) -> EchoClient<CountBytes<S>>
+where
+    S: Service<http::Request<Body>, Response = http::Response<Body>>,
+    S::Error: Into<StdError>,
+    S::Future: Send + 'static,
+{
+    let mut client = EchoClient::new(CountBytes::new(channel));
+    if let Some(encoding) = compression.encoding() {
+        // tonic servers compress responses with the first encoding the client accepts
+        client = client.send_compressed(encoding).accept_compressed(encoding);
+    }
+    for encoding in ACCEPTED_ENCODINGS {
+        client = client.accept_compressed(encoding);
+    }
+    client
+}
+
+/// Counts of the messages sent in one direction of an RPC.
+#[derive(Debug, Default)]
+struct ByteCounts {
+    messages: AtomicUsize,
+    /// Message bytes as sent on the wire, after compression if it was used.
+    compressed_bytes: AtomicUsize,
+    /// Message bytes after decompressing.
+    uncompressed_bytes: AtomicUsize,
+}
+
+impl ByteCounts {
+    fn add(&self, compressed_bytes: usize, uncompressed_bytes: usize) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.compressed_bytes
+            .fetch_add(compressed_bytes, Ordering::Relaxed);
+        self.uncompressed_bytes
+            .fetch_add(uncompressed_bytes, Ordering::Relaxed);
+    }
+}
+
+impl std::fmt::Display for ByteCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "messages={} compressed_bytes={} uncompressed_bytes={}",
+            self.messages.load(Ordering::Relaxed),
+            self.compressed_bytes.load(Ordering::Relaxed),
+            self.uncompressed_bytes.load(Ordering::Relaxed)
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct RpcBytes {
+    request: ByteCounts,
+    response: ByteCounts,
+}
+
+/// Splits a body into gRPC length-prefixed messages and counts their sizes.
+#[derive(Debug)]
+struct MessageParser {
+    compression: Compression,
+    buffer: BytesMut,
+}
+
+impl MessageParser {
+    fn new(compression: Compression) -> Self {
+        Self {
+            compression,
+            buffer: BytesMut::new(),
+        }
+    }
+
+    /// Adds `data` from the body, and counts every message that is now complete.
+    fn add(&mut self, data: &[u8], counts: &ByteCounts) {
+        self.buffer.extend_from_slice(data);
+        while self.buffer.len() >= MESSAGE_HEADER_LEN {
+            let compressed = self.buffer[0] == 1;
+            let length = u32::from_be_bytes([
+                self.buffer[1],
+                self.buffer[2],
+                self.buffer[3],
+                self.buffer[4],
+            ]) as usize;
+            if self.buffer.len() < MESSAGE_HEADER_LEN + length {
+                break;
+            }
+            let message = self.buffer.split_to(MESSAGE_HEADER_LEN + length);
+            let message = &message[MESSAGE_HEADER_LEN..];
+            let uncompressed_length = if compressed {
+                // count the compressed length if the message cannot be decompressed
+                decompressed_len(self.compression, message).unwrap_or(length)
+            } else {
+                length
+            };
+            counts.add(length, uncompressed_length);
+        }
+    }
+}
+
+fn decompressed_len(compression: Compression, message: &[u8]) -> Option<usize> {
+    let result = match compression {
+        Compression::None => return None,
+        Compression::Gzip => io::copy(&mut flate2::read::GzDecoder::new(message), &mut io::sink()),
+        Compression::Zstd => zstd::stream::read::Decoder::new(message)
+            .and_then(|mut decoder| io::copy(&mut decoder, &mut io::sink())),
+    };
+    result.ok().and_then(|length| usize::try_from(length).ok())
+}
+
+/// A body that counts the messages it contains. The response body prints the counts for the RPC
+/// when it is dropped.
+struct CountingBody {
+    inner: Body,
+    parser: MessageParser,
+    rpc_bytes: Arc<RpcBytes>,
+    /// Set for the response body: the path of the RPC to print when dropped.
+    report_path: Option<String>,
+}
+
+impl http_body::Body for CountingBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let result = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &result
+            && let Some(data) = frame.data_ref()
+        {
+            let this = &mut *self;
+            let counts = if this.report_path.is_some() {
+                &this.rpc_bytes.response
+            } else {
+                &this.rpc_bytes.request
+            };
+            this.parser.add(data, counts);
+        }
+        result
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for CountingBody {
+    fn drop(&mut self) {
+        if let Some(path) = &self.report_path {
+            println!(
+                "{} rpc {path} request: {} response: {}",
+                now_formatted(),
+                self.rpc_bytes.request,
+                self.rpc_bytes.response
+            );
+        }
+    }
+}
+
+/// Wraps a client channel or a server to print the compressed and uncompressed bytes of each RPC.
+#[derive(Debug, Clone)]
+pub struct CountBytes<S> {
+    inner: S,
+}
+
+impl<S> CountBytes<S> {
+    #[must_use]
+    pub const fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S: NamedService> NamedService for CountBytes<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S> Service<http::Request<Body>> for CountBytes<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        let path = request.uri().path().to_string();
+        let rpc_bytes = Arc::new(RpcBytes::default());
+        let request_compression = Compression::from_headers(request.headers());
+        let request_rpc_bytes = rpc_bytes.clone();
+        let request = request.map(|body| {
+            Body::new(CountingBody {
+                inner: body,
+                parser: MessageParser::new(request_compression),
+                rpc_bytes: request_rpc_bytes,
+                report_path: None,
+            })
+        });
+
+        let future = self.inner.call(request);
+        Box::pin(async move {
+            let response = future.await?;
+            let response_compression = Compression::from_headers(response.headers());
+            Ok(response.map(|body| {
+                Body::new(CountingBody {
+                    inner: body,
+                    parser: MessageParser::new(response_compression),
+                    rpc_bytes,
+                    report_path: Some(path),
+                })
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn message_bytes(compressed: bool, message: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![u8::from(compressed)];
+        bytes.extend_from_slice(&u32::try_from(message.len()).unwrap().to_be_bytes());
+        bytes.extend_from_slice(message);
+        bytes
+    }
+
+    #[test]
+    fn test_message_parser_counts_split_messages() {
+        let uncompressed = vec![b'x'; 1000];
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(&uncompressed).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut body = message_bytes(true, &compressed);
+        body.extend(message_bytes(false, b"hello"));
+
+        // split the body in the middle of the first message's header
+        let counts = ByteCounts::default();
+        let mut parser = MessageParser::new(Compression::Gzip);
+        parser.add(&body[..3], &counts);
+        assert_eq!(counts.messages.load(Ordering::Relaxed), 0);
+        parser.add(&body[3..], &counts);
+
+        assert_eq!(
+            counts.to_string(),
+            format!(
+                "messages=2 compressed_bytes={} uncompressed_bytes=1005",
+                compressed.len() + 5
+            )
+        );
+    }
+}
@@ -0,0 +1,317 @@
+//! Per-request controls for the Echo service, set by the client in request metadata.
+//!
+//! Clients can make one call slow, fail, or return a large response without restarting the
+//! server, so tests of retries and timeouts can share one server. The headers override the
+//! server's command line flags for that call.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use prost::Message;
+use tonic::Code;
+use tonic::Status;
+use tonic::metadata::MetadataMap;
+
+use crate::echopb::EchoRequest;
+use crate::echopb::EchoResponse;
+use crate::fault::parse_code;
+
+/// Milliseconds to wait before sending each response.
+pub const DELAY_MS_HEADER: &str = "x-echo-delay-ms";
+/// The status code to fail the RPC with, as a name like `UNAVAILABLE` or a number. `OK` succeeds
+/// even if the server returns errors with details.
+pub const STATUS_CODE_HEADER: &str = "x-echo-status-code";
+/// `true` or `false`: overrides the server's `--err-details` flag.
+pub const ERROR_DETAILS_HEADER: &str = "x-echo-error-details";
+/// Pads or truncates the output of each response to this many bytes.
+pub const RESPONSE_SIZE_HEADER: &str = "x-echo-response-size";
+
+/// The largest `EchoRequest.response_size` and `x-echo-response-size`: gRPC clients reject
+/// messages over 4 MiB by default. The whole response must also fit in [`MAX_RESPONSE_SIZE`].
+pub const MAX_PAYLOAD_SIZE: u32 = 4 * 1024 * 1024;
+
+/// The largest encoded response before the server sets its times, leaving them room under the
+/// 4 MiB that gRPC clients accept by default.
+pub const MAX_RESPONSE_SIZE: usize = 4 * 1024 * 1024 - 64;
+
+/// The longest delay a client can request, so one call cannot hold the server indefinitely.
+pub const MAX_DELAY: Duration = Duration::from_mins(1);
+
+/// The controls read from the metadata of one request. `None` fields were not set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EchoControls {
+    pub delay: Option<Duration>,
+    pub status_code: Option<Code>,
+    pub error_details: Option<bool>,
+    pub response_size: Option<usize>,
+}
+
+impl EchoControls {
+    /// Reads the `x-echo-*` headers. Returns `INVALID_ARGUMENT` if a header has an invalid value,
+    /// or asks for a delay over [`MAX_DELAY`] or a size over [`MAX_PAYLOAD_SIZE`].
+    pub fn from_metadata(metadata: &MetadataMap) -> Result<Self, Status> {
+        let controls = Self {
+            delay: parse_header(metadata, DELAY_MS_HEADER, u64::from_str)?
+                .map(Duration::from_millis),
+            status_code: parse_header(metadata, STATUS_CODE_HEADER, parse_code)?,
+            error_details: parse_header(metadata, ERROR_DETAILS_HEADER, bool::from_str)?,
+            response_size: parse_header(metadata, RESPONSE_SIZE_HEADER, usize::from_str)?,
+        };
+        if controls.delay.is_some_and(|delay| delay > MAX_DELAY) {
+            return Err(Status::invalid_argument(format!(
+                "{DELAY_MS_HEADER} must be at most {}",
+                MAX_DELAY.as_millis()
+            )));
+        }
+        if controls
+            .response_size
+            .is_some_and(|size| size > MAX_PAYLOAD_SIZE as usize)
+        {
+            return Err(Status::invalid_argument(format!(
+                "{RESPONSE_SIZE_HEADER} must be at most {MAX_PAYLOAD_SIZE}"
+            )));
+        }
+        Ok(controls)
+    }
+
+    /// Returns true if this call should fail with error details, given the server's default.
+    #[must_use]
+    pub fn error_details(&self, server_err_details: bool) -> bool {
+        self.error_details.unwrap_or(server_err_details)
+    }
+
+    /// Returns the code to fail this call with, or `None` if it should succeed. Without
+    /// `x-echo-status-code`, calls with error details fail with `INTERNAL`.
+    #[must_use]
+    pub fn error_code(&self, server_err_details: bool) -> Option<Code> {
+        self.status_code.map_or_else(
+            || {
+                self.error_details(server_err_details)
+                    .then_some(Code::Internal)
+            },
+            |code| (code != Code::Ok).then_some(code),
+        )
+    }
+
+    /// Returns the output that echoes `input`, resized if requested.
+    #[must_use]
+    pub fn output(&self, input: &str) -> String {
+        let mut output = format!("echoed: {input}");
+        if let Some(size) = self.response_size {
+            output.truncate(output.floor_char_boundary(size));
+            let padding = size - output.len();
+            output.extend(std::iter::repeat_n('.', padding));
+        }
+        output
+    }
+
+    /// Returns the response that echoes `request`. The payload is resized to
+    /// `request.response_size`, and the send time, sequence number and attributes are copied.
+    /// The server times are not set. Returns `INVALID_ARGUMENT` if the size is larger than
+    /// [`MAX_PAYLOAD_SIZE`], or the whole response is larger than [`MAX_RESPONSE_SIZE`].
+    pub fn response(&self, request: &EchoRequest) -> Result<EchoResponse, Status> {
+        let mut payload = request.payload.clone();
+        if let Some(size) = request.response_size {
+            if size > MAX_PAYLOAD_SIZE {
+                return Err(Status::invalid_argument(format!(
+                    "response_size={size} must be at most {MAX_PAYLOAD_SIZE}"
+                )));
+            }
+            payload.resize(size as usize, 0);
+        }
+        let response = EchoResponse {
+            output: self.output(&request.input),
+            payload,
+            send_time_unix_nanos: request.send_time_unix_nanos,
+            sequence: request.sequence,
+            attributes: request.attributes.clone(),
+            ..EchoResponse::default()
+        };
+        check_response_size(&response)?;
+        Ok(response)
+    }
+}
+
+/// Returns `INVALID_ARGUMENT` if `response` is larger than [`MAX_RESPONSE_SIZE`] encoded, so
+/// callers get a clear error instead of a response their client refuses to decode.
+pub fn check_response_size(response: &impl Message) -> Result<(), Status> {
+    let size = response.encoded_len();
+    if size > MAX_RESPONSE_SIZE {
+        return Err(Status::invalid_argument(format!(
+            "the response would be {size} bytes, which is more than {MAX_RESPONSE_SIZE}: \
+             reduce {RESPONSE_SIZE_HEADER}, response_size or the request"
+        )));
+    }
+    Ok(())
+}
+
+fn parse_header<T, E: std::fmt::Display>(
+    metadata: &MetadataMap,
+    name: &str,
+    parse: impl FnOnce(&str) -> Result<T, E>,
+) -> Result<Option<T>, Status> {
+    let Some(value) = metadata.get(name) else {
+        return Ok(None);
+    };
+    let invalid = |err: &dyn std::fmt::Display| {
+        Status::invalid_argument(format!("invalid {name} header {value:?}: {err}"))
+    };
+    let value_str = value.to_str().map_err(|err| invalid(&err))?;
+    parse(value_str.trim())
+        .map(Some)
+        .map_err(|err| invalid(&err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_metadata() {
+        assert_eq!(
+            EchoControls::from_metadata(&MetadataMap::new()).unwrap(),
+            EchoControls::default()
+        );
+
+        let mut metadata = MetadataMap::new();
+        metadata.insert(DELAY_MS_HEADER, "250".parse().unwrap());
+        metadata.insert(STATUS_CODE_HEADER, "not_found".parse().unwrap());
+        metadata.insert(ERROR_DETAILS_HEADER, "false".parse().unwrap());
+        metadata.insert(RESPONSE_SIZE_HEADER, "10".parse().unwrap());
+        assert_eq!(
+            EchoControls::from_metadata(&metadata).unwrap(),
+            EchoControls {
+                delay: Some(Duration::from_millis(250)),
+                status_code: Some(Code::NotFound),
+                error_details: Some(false),
+                response_size: Some(10),
+            }
+        );
+
+        for (header, value) in [
+            (RESPONSE_SIZE_HEADER, "-1".to_string()),
+            (RESPONSE_SIZE_HEADER, u64::MAX.to_string()),
+            (RESPONSE_SIZE_HEADER, (MAX_PAYLOAD_SIZE + 1).to_string()),
+            (DELAY_MS_HEADER, (MAX_DELAY.as_millis() + 1).to_string()),
+        ] {
+            let mut metadata = MetadataMap::new();
+            metadata.insert(header, value.parse().unwrap());
+            let status = EchoControls::from_metadata(&metadata).unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+            assert!(status.message().contains(header), "{status:?}");
+        }
+
+        let mut metadata = MetadataMap::new();
+        metadata.insert(
+            RESPONSE_SIZE_HEADER,
+            MAX_PAYLOAD_SIZE.to_string().parse().unwrap(),
+        );
+        let controls = EchoControls::from_metadata(&metadata).unwrap();
+        assert_eq!(controls.output("").len(), MAX_PAYLOAD_SIZE as usize);
+    }
+
+    #[test]
+    fn test_error_code() {
+        let controls = EchoControls::default();
+        assert_eq!(controls.error_code(false), None);
+        assert_eq!(controls.error_code(true), Some(Code::Internal));
+
+        let controls = EchoControls {
+            error_details: Some(false),
+            ..EchoControls::default()
+        };
+        assert_eq!(controls.error_code(true), None);
+
+        let controls = EchoControls {
+            status_code: Some(Code::Ok),
+            ..EchoControls::default()
+        };
+        assert_eq!(controls.error_code(true), None);
+
+        let controls = EchoControls {
+            status_code: Some(Code::Unavailable),
+            ..EchoControls::default()
+        };
+        assert_eq!(controls.error_code(false), Some(Code::Unavailable));
+    }
+
+    #[test]
+    fn test_response_size() {
+        let response = |response_size| {
+            EchoControls {
+                response_size,
+                ..EchoControls::default()
+            }
+            .output("héllo")
+        };
+        assert_eq!(response(None), "echoed: héllo");
+        assert_eq!(response(Some(16)), "echoed: héllo..");
+        // truncates to a character boundary and pads the rest
+        assert_eq!(response(Some(10)), "echoed: h.");
+        assert_eq!(response(Some(0)), "");
+    }
+
+    #[test]
+    fn test_response_echoes_request() {
+        let request = EchoRequest {
+            input: "hello".to_string(),
+            payload: vec![1, 2, 3],
+            response_size: None,
+            send_time_unix_nanos: 1_700_000_000_000_000_000,
+            sequence: 42,
+            delay: None,
+            attributes: Some(prost_types::Struct {
+                fields: [("key".to_string(), "value".into())].into(),
+            }),
+        };
+        let response = EchoControls::default().response(&request).unwrap();
+        assert_eq!(response.output, "echoed: hello");
+        assert_eq!(response.payload, [1, 2, 3]);
+        assert_eq!(response.send_time_unix_nanos, request.send_time_unix_nanos);
+        assert_eq!(response.sequence, 42);
+        assert_eq!(response.attributes, request.attributes);
+        assert_eq!(response.server_receive_time, None);
+
+        let resized = |response_size| {
+            EchoControls::default()
+                .response(&EchoRequest {
+                    response_size: Some(response_size),
+                    ..request.clone()
+                })
+                .map(|response| response.payload)
+        };
+        assert_eq!(resized(5).unwrap(), [1, 2, 3, 0, 0]);
+        assert_eq!(resized(2).unwrap(), [1, 2]);
+        assert!(resized(0).unwrap().is_empty());
+        assert_eq!(
+            resized(MAX_PAYLOAD_SIZE + 1).unwrap_err().code(),
+            Code::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn test_response_size_limits_whole_response() {
+        let controls = EchoControls {
+            response_size: Some(MAX_RESPONSE_SIZE / 2),
+            ..EchoControls::default()
+        };
+        let request = |payload_size: u32| EchoRequest {
+            response_size: Some(payload_size),
+            ..EchoRequest::default()
+        };
+        let half = u32::try_from(MAX_RESPONSE_SIZE / 2).unwrap();
+        let response = controls.response(&request(half - 16)).unwrap();
+        assert!(response.encoded_len() <= MAX_RESPONSE_SIZE);
+
+        // each field is under MAX_PAYLOAD_SIZE, but together they are too large
+        let status = controls.response(&request(half)).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let status = EchoControls {
+            response_size: Some(MAX_PAYLOAD_SIZE as usize),
+            ..EchoControls::default()
+        }
+        .response(&request(MAX_PAYLOAD_SIZE))
+        .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+}
@@ -0,0 +1,251 @@
+//! Echoes request metadata back to the caller in response headers and trailers.
+//!
+//! This shows which headers reached the server through proxies and interceptors. Clients select
+//! the metadata to echo with the `x-echo-metadata` request header. Tonic services can only set
+//! response headers, so [`EchoService`](crate::echo_service::EchoService) attaches the trailers
+//! as a [`TrailerMetadata`] response extension, and [`ResponseTrailers`] adds them to the end of
+//! the response body. [`PrintMetadata`] wraps a client channel to print the response headers and
+//! trailers it receives.
+
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+use bytes::Bytes;
+use http_body::Frame;
+use http_body::SizeHint;
+use tonic::Status;
+use tonic::body::Body;
+use tonic::codegen::BoxFuture;
+use tonic::codegen::Service;
+use tonic::codegen::http;
+use tonic::metadata::KeyAndValueRef;
+use tonic::metadata::MetadataMap;
+use tonic::server::NamedService;
+
+use crate::now_formatted;
+
+/// Selects the request metadata to echo: `*` for all of it, or a comma-separated list of keys.
+pub const ECHO_METADATA_HEADER: &str = "x-echo-metadata";
+
+/// Request headers that are never echoed, because they describe the request itself.
+const RESERVED_HEADERS: [&str; 3] = ["content-type", "te", "user-agent"];
+
+/// Returns the metadata selected by the `x-echo-metadata` header of `request_metadata`, or `None`
+/// if it is not set. Includes binary `-bin` keys, but not `grpc-*` or other reserved headers.
+pub fn echoed_metadata(request_metadata: &MetadataMap) -> Result<Option<MetadataMap>, Status> {
+    let Some(selection) = request_metadata.get(ECHO_METADATA_HEADER) else {
+        return Ok(None);
+    };
+    let selection = selection.to_str().map_err(|err| {
+        Status::invalid_argument(format!(
+            "invalid {ECHO_METADATA_HEADER} header {selection:?}: {err}"
+        ))
+    })?;
+    let selected_keys: Vec<String> = selection
+        .split(',')
+        .map(|key| key.trim().to_ascii_lowercase())
+        .filter(|key| !key.is_empty())
+        .collect();
+    let is_selected = |key: &str| {
+        !(key.starts_with("grpc-") || RESERVED_HEADERS.contains(&key))
+            && selected_keys
+                .iter()
+                .any(|selected_key| selected_key == "*" || selected_key == key)
+    };
+
+    let mut metadata = MetadataMap::new();
+    for key_and_value in request_metadata.iter() {
+        match key_and_value {
+            KeyAndValueRef::Ascii(key, value) if is_selected(key.as_str()) => {
+                metadata.append(key.clone(), value.clone());
+            }
+            KeyAndValueRef::Binary(key, value) if is_selected(key.as_str()) => {
+                metadata.append_bin(key.clone(), value.clone());
+            }
+            _ => {}
+        }
+    }
+    Ok(Some(metadata))
+}
+
+/// A response extension with metadata for [`ResponseTrailers`] to add to the trailers.
+#[derive(Debug, Clone)]
+pub struct TrailerMetadata(pub MetadataMap);
+
+/// A body that calls `on_trailers` with its trailers, if it has any.
+struct InspectTrailersBody {
+    inner: Body,
+    on_trailers: Box<dyn FnMut(&mut http::HeaderMap) + Send>,
+}
+
+impl http_body::Body for InspectTrailersBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let mut result = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &mut result
+            && let Some(trailers) = frame.trailers_mut()
+        {
+            (self.on_trailers)(trailers);
+        }
+        result
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Wraps a server to add the [`TrailerMetadata`] extension of each response to its trailers.
+#[derive(Debug, Clone)]
+pub struct ResponseTrailers<S> {
+    inner: S,
+}
+
+impl<S> ResponseTrailers<S> {
+    #[must_use]
+    pub const fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S: NamedService> NamedService for ResponseTrailers<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S> Service<http::Request<Body>> for ResponseTrailers<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        let future = self.inner.call(request);
+        Box::pin(async move {
+            let mut response = future.await?;
+            let Some(TrailerMetadata(metadata)) = response.extensions_mut().remove() else {
+                return Ok(response);
+            };
+            let mut extra_trailers = Some(metadata.into_headers());
+            Ok(response.map(|body| {
+                Body::new(InspectTrailersBody {
+                    inner: body,
+                    on_trailers: Box::new(move |trailers| {
+                        if let Some(extra_trailers) = extra_trailers.take() {
+                            trailers.extend(extra_trailers);
+                        }
+                    }),
+                })
+            }))
+        })
+    }
+}
+
+/// Wraps a client channel to print the headers and trailers of each response.
+#[derive(Debug, Clone)]
+pub struct PrintMetadata<S> {
+    inner: S,
+}
+
+impl<S> PrintMetadata<S> {
+    #[must_use]
+    pub const fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S> Service<http::Request<Body>> for PrintMetadata<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        let path = request.uri().path().to_string();
+        let future = self.inner.call(request);
+        Box::pin(async move {
+            let response = future.await?;
+            print_headers(&path, "header", response.headers());
+            Ok(response.map(|body| {
+                Body::new(InspectTrailersBody {
+                    inner: body,
+                    on_trailers: Box::new(move |trailers| {
+                        print_headers(&path, "trailer", trailers);
+                    }),
+                })
+            }))
+        })
+    }
+}
+
+fn print_headers(path: &str, kind: &str, headers: &http::HeaderMap) {
+    let now = now_formatted();
+    for (name, value) in headers {
+        println!("{now} rpc {path} response {kind} {name}: {value:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::metadata::MetadataValue;
+
+    use super::*;
+
+    #[test]
+    fn test_echoed_metadata() {
+        let mut request_metadata = MetadataMap::new();
+        assert!(echoed_metadata(&request_metadata).unwrap().is_none());
+
+        request_metadata.insert("x-a", "1".parse().unwrap());
+        request_metadata.append("x-a", "2".parse().unwrap());
+        request_metadata.insert("x-b", "3".parse().unwrap());
+        request_metadata.insert_bin("x-c-bin", MetadataValue::from_bytes(b"\x00\xff"));
+        request_metadata.insert("grpc-timeout", "1S".parse().unwrap());
+        request_metadata.insert("user-agent", "test".parse().unwrap());
+
+        request_metadata.insert(ECHO_METADATA_HEADER, "X-A, x-c-bin".parse().unwrap());
+        let metadata = echoed_metadata(&request_metadata).unwrap().unwrap();
+        assert_eq!(metadata.get_all("x-a").iter().count(), 2);
+        assert_eq!(
+            metadata.get_bin("x-c-bin").unwrap().to_bytes().unwrap(),
+            &b"\x00\xff"[..]
+        );
+        assert_eq!(metadata.len(), 3);
+
+        request_metadata.insert(ECHO_METADATA_HEADER, "*".parse().unwrap());
+        let metadata = echoed_metadata(&request_metadata).unwrap().unwrap();
+        let mut keys = metadata
+            .keys()
+            .map(|key| match key {
+                tonic::metadata::KeyRef::Ascii(key) => key.as_str(),
+                tonic::metadata::KeyRef::Binary(key) => key.as_str(),
+            })
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys.dedup();
+        assert_eq!(keys, ["x-a", "x-b", "x-c-bin", ECHO_METADATA_HEADER]);
+    }
+}
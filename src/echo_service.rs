@@ -0,0 +1,1636 @@
+//! The implementation of the `echopb.Echo` service.
+//!
+//! [`EchoService`] implements the `Echo` trait from every generated copy of `echo.proto`, and from
+//! the manually defined `json.echopb.Echo` service. Each trait delegates to the generic methods,
+//! which convert the messages to and from `echopb` types.
+
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::LazyLock;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use prost::Message;
+use prost::Name;
+use tokio_stream::Stream;
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Request;
+use tonic::Response;
+use tonic::Status;
+use tonic::Streaming;
+use tonic::metadata::MetadataMap;
+
+use crate::PeerIdentity;
+use crate::any_registry::AnyRegistry;
+use crate::backpressure::DEFAULT_STREAM_CHANNEL_CAPACITY;
+use crate::backpressure::TimedSender;
+use crate::chat_room::ChatRooms;
+use crate::chat_room::DEFAULT_ROOM_BUFFER;
+use crate::chat_room::RoomEvent;
+use crate::chat_room::RoomMembership;
+use crate::chat_room::room_name;
+use crate::custom_codec_echopb;
+use crate::echo_controls::EchoControls;
+use crate::echo_controls::MAX_DELAY;
+use crate::echo_controls::STATUS_CODE_HEADER;
+use crate::echo_controls::check_response_size;
+use crate::echo_metadata::TrailerMetadata;
+use crate::echo_metadata::echoed_metadata;
+use crate::echopb;
+use crate::echopb::EchoCollectResponse;
+use crate::echopb::EchoRepeatRequest;
+use crate::echopb::EchoRequest;
+use crate::echopb::EchoResponse;
+use crate::echopb::{Example1, Example2};
+use crate::fault::FaultInjector;
+use crate::json_codec_echopb;
+use crate::json_echopb;
+use crate::now_formatted;
+use crate::raw_codec_echopb;
+use crate::rich_error::RichErrorBuilder;
+use crate::rich_error::decode_details;
+use crate::shutdown::RpcGuard;
+use crate::shutdown::Shutdown;
+use crate::unix_nanos_now;
+
+/// Decodes the error details for logging.
+static ERROR_DETAILS_REGISTRY: LazyLock<AnyRegistry> =
+    LazyLock::new(AnyRegistry::with_error_details);
+
+/// The most responses one `EchoRepeat` call can ask for.
+pub const MAX_REPEAT_COUNT: u32 = 1000;
+
+/// The most requests one `EchoCollect` call can send.
+pub const MAX_COLLECT_COUNT: usize = 1000;
+
+/// The most input bytes one `EchoCollect` call can send.
+///
+/// The response holds the inputs twice, so this keeps it under the 4 MiB that gRPC clients accept
+/// by default, unless `x-echo-response-size` pads the output.
+pub const MAX_COLLECT_INPUT_BYTES: usize = 1024 * 1024;
+
+/// The response stream returned by `EchoBiDir` and `EchoRepeat`.
+pub type EchoBiDirStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send + 'static>>;
+
+/// The details to attach to errors, with example contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ErrorDetailType {
+    Example1,
+    Example2,
+    BadRequest,
+    RetryInfo,
+    ErrorInfo,
+    QuotaFailure,
+    DebugInfo,
+    LocalizedMessage,
+    ResourceInfo,
+}
+
+impl ErrorDetailType {
+    /// The details attached to errors by default.
+    pub const DEFAULT: [Self; 2] = [Self::Example1, Self::Example2];
+
+    /// Adds an example detail of this type to `builder`.
+    #[must_use]
+    pub fn add_example(self, builder: RichErrorBuilder) -> RichErrorBuilder {
+        match self {
+            Self::Example1 => builder.detail(&Example1 { int64_value: 99 }),
+            Self::Example2 => builder.detail(&Example2 {
+                float64_value: 1.234,
+            }),
+            Self::BadRequest => builder.bad_request(tonic_types::BadRequest::with_violation(
+                "input",
+                "example violation",
+            )),
+            Self::RetryInfo => {
+                builder.retry_info(tonic_types::RetryInfo::new(Some(Duration::from_secs(1))))
+            }
+            Self::ErrorInfo => builder.error_info(tonic_types::ErrorInfo::new(
+                "EXAMPLE_ERROR",
+                "rustgrpcdemo.example.com",
+                HashMap::from([("service".to_string(), "echopb.Echo".to_string())]),
+            )),
+            Self::QuotaFailure => builder.quota_failure(tonic_types::QuotaFailure::with_violation(
+                "clientip:127.0.0.1",
+                "example quota",
+            )),
+            Self::DebugInfo => builder.debug_info(tonic_types::DebugInfo::new(
+                vec!["echo_service.rs:handle_echo".to_string()],
+                "example debug info",
+            )),
+            Self::LocalizedMessage => builder.localized_message(
+                tonic_types::LocalizedMessage::new("en-US", "example localized message"),
+            ),
+            Self::ResourceInfo => builder.resource_info(tonic_types::ResourceInfo::new(
+                "echopb.EchoRequest",
+                "example",
+                "rustgrpcdemo",
+                "example resource",
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct EchoService {
+    err_details: bool,
+    error_detail_types: Vec<ErrorDetailType>,
+    shutdown: Shutdown,
+    faults: Option<Arc<FaultInjector>>,
+    rooms: Arc<ChatRooms>,
+    stream_channel_capacity: NonZeroUsize,
+}
+
+impl EchoService {
+    /// Returns a service that returns an error with details from `echo` if `err_details` is set.
+    /// RPCs are tracked by `shutdown`.
+    #[must_use]
+    pub fn new(err_details: bool, shutdown: Shutdown) -> Self {
+        Self {
+            err_details,
+            error_detail_types: Vec::new(),
+            shutdown,
+            faults: None,
+            rooms: Arc::new(ChatRooms::new(DEFAULT_ROOM_BUFFER)),
+            stream_channel_capacity: DEFAULT_STREAM_CHANNEL_CAPACITY,
+        }
+    }
+
+    /// Buffers up to `capacity` responses for each `EchoBiDir` and `EchoRepeat` stream before
+    /// sends wait for the client to read, instead of [`DEFAULT_STREAM_CHANNEL_CAPACITY`].
+    #[must_use]
+    pub const fn with_stream_channel_capacity(mut self, capacity: NonZeroUsize) -> Self {
+        self.stream_channel_capacity = capacity;
+        self
+    }
+
+    /// Buffers up to `room_buffer` messages for each member of an `EchoBiDir` chat room, instead
+    /// of [`DEFAULT_ROOM_BUFFER`].
+    #[must_use]
+    pub fn with_room_buffer(mut self, room_buffer: NonZeroUsize) -> Self {
+        self.rooms = Arc::new(ChatRooms::new(room_buffer));
+        self
+    }
+
+    /// Attaches `error_detail_types` to errors with details, instead of
+    /// [`ErrorDetailType::DEFAULT`].
+    #[must_use]
+    pub fn with_error_detail_types(mut self, error_detail_types: Vec<ErrorDetailType>) -> Self {
+        self.error_detail_types = error_detail_types;
+        self
+    }
+
+    /// Injects faults into RPCs with `faults`.
+    #[must_use]
+    pub fn with_faults(mut self, faults: FaultInjector) -> Self {
+        self.faults = Some(Arc::new(faults));
+        self
+    }
+
+    /// Returns the injected error for a new RPC, if it should fail.
+    fn injected_error(&self, rpc_name: &str) -> Option<Status> {
+        let status = self.faults.as_ref()?.error()?;
+        println!("{rpc_name} injecting error: {status:?}");
+        Some(status)
+    }
+
+    /// Implements `Echo` for any request and response types that convert from and to `echopb`.
+    /// The `x-echo-*` request headers in [`crate::echo_controls`] override the server's flags,
+    /// and `x-echo-metadata` selects the metadata to echo with [`echo_metadata`].
+    pub async fn handle_echo<Req, Resp>(
+        &self,
+        request: Request<Req>,
+    ) -> Result<Response<Resp>, Status>
+    where
+        Req: Into<EchoRequest>,
+        Resp: From<EchoResponse>,
+    {
+        let mut rpc_guard = self.shutdown.start_rpc();
+        let receive_time = timestamp_now();
+        let request = request.map(Into::into);
+        println!("echo request.msg={:?}", request.get_ref());
+        if let Some(peer_identity) = PeerIdentity::from_request(&request) {
+            println!("echo peer_identity: {peer_identity}");
+        }
+        let controls = EchoControls::from_metadata(request.metadata())?;
+        if controls != EchoControls::default() {
+            println!("echo controls: {controls:?}");
+        }
+        let request_delay = requested_delay(request.get_ref())?;
+        let echoed_metadata = echoed_metadata(request.metadata())?;
+        if let Some(status) = self.injected_error("echo") {
+            return Err(status);
+        }
+        let mut wait = controls.delay.unwrap_or_default() + request_delay.unwrap_or_default();
+        if let Some(latency) = self.faults.as_ref().and_then(|faults| faults.latency()) {
+            println!("echo injecting latency: {latency:?}");
+            wait += latency;
+        }
+        sleep_unless_aborting("echo", wait, &self.shutdown, &mut rpc_guard).await?;
+        self.check_requested_error(&controls)?;
+
+        log_one_way_latency("echo", request.get_ref());
+        let response = with_server_times(controls.response(request.get_ref())?, receive_time);
+        Ok(echo_metadata(
+            Response::new(response.into()),
+            echoed_metadata,
+        ))
+    }
+
+    /// Returns the error requested by `controls` or the server's flags, if the call should fail.
+    fn check_requested_error(&self, controls: &EchoControls) -> Result<(), Status> {
+        let Some(code) = controls.error_code(self.err_details) else {
+            return Ok(());
+        };
+        if controls.error_details(self.err_details) {
+            return Err(self.status_with_details(code));
+        }
+        Err(Status::new(
+            code,
+            format!("error requested by {STATUS_CODE_HEADER}"),
+        ))
+    }
+
+    /// Returns an error with code `code` and an example of each detail type.
+    fn status_with_details(&self, code: tonic::Code) -> Status {
+        let detail_types: &[ErrorDetailType] = if self.error_detail_types.is_empty() {
+            &ErrorDetailType::DEFAULT
+        } else {
+            &self.error_detail_types
+        };
+        let builder =
+            RichErrorBuilder::new(code, format!("error with {} details", detail_types.len()));
+        let status = detail_types
+            .iter()
+            .fold(builder, |builder, detail_type| {
+                detail_type.add_example(builder)
+            })
+            .build();
+        log_details(&status);
+        status
+    }
+
+    /// Implements `EchoBiDir` for any request and response types that convert from and to
+    /// `echopb`. The request stream is usually [`Streaming`]. The `x-echo-*` request headers
+    /// apply to every response on the stream. If the `x-echo-room` header is set, the stream
+    /// joins that room in [`crate::chat_room`] instead of echoing.
+    pub fn handle_echo_bi_dir<S, Req, Resp>(
+        &self,
+        request: Request<S>,
+    ) -> Result<Response<EchoBiDirStream<Resp>>, Status>
+    where
+        S: Stream<Item = Result<Req, Status>> + Send + 'static,
+        Req: Into<EchoRequest> + Send + 'static,
+        Resp: From<EchoResponse> + Send + 'static,
+    {
+        println!("echo_bi_dir: starting new echo_bi_dir stream ...");
+        if let Some(peer_identity) = PeerIdentity::from_request(&request) {
+            println!("echo_bi_dir peer_identity: {peer_identity}");
+        }
+        let controls = EchoControls::from_metadata(request.metadata())?;
+        if controls != EchoControls::default() {
+            println!("echo_bi_dir controls: {controls:?}");
+        }
+        let echoed_metadata = echoed_metadata(request.metadata())?;
+        if let Some(status) = self.injected_error("echo_bi_dir") {
+            return Err(status);
+        }
+        self.check_requested_error(&controls)?;
+        let membership = room_name(request.metadata())?
+            .map(|room| self.rooms.join(&room, member_name(&request)));
+        let mut rpc_guard = self.shutdown.start_rpc();
+        let shutdown = self.shutdown.clone();
+        let faults = self.faults.clone();
+        let request_stream = request.into_inner();
+        let (response_stream_sender, response_stream_rx) =
+            tokio::sync::mpsc::channel(self.stream_channel_capacity.get());
+        let mut response_stream_sender = TimedSender::new(response_stream_sender);
+
+        tokio::spawn(async move {
+            let stream_result = if let Some(membership) = membership {
+                do_room_bi_dir(
+                    request_stream,
+                    membership,
+                    &mut response_stream_sender,
+                    &shutdown,
+                    &mut rpc_guard,
+                )
+                .await
+            } else {
+                do_echo_bi_dir(
+                    request_stream,
+                    &mut response_stream_sender,
+                    &shutdown,
+                    &mut rpc_guard,
+                    faults.as_deref(),
+                    controls,
+                )
+                .await
+            };
+            if let Err(stream_err) = stream_result {
+                eprintln!("do_echo_bi_dir returned error; sending to caller: {stream_err}");
+                // keep the code, so invalid requests fail with INVALID_ARGUMENT
+                let final_send_result = send_response(
+                    &mut response_stream_sender,
+                    Err(stream_err),
+                    &shutdown,
+                    &mut rpc_guard,
+                )
+                .await;
+                if let Err(send_err) = final_send_result {
+                    eprintln!("echo_bi_dir failed sending error to caller; send error: {send_err}");
+                }
+            }
+            println!(
+                "{} echo_bi_dir stream stats: {}",
+                now_formatted(),
+                response_stream_sender.stats()
+            );
+        });
+
+        let response_stream = ReceiverStream::new(response_stream_rx)
+            .map(|response_result| response_result.map(Resp::from));
+        Ok(echo_metadata(
+            Response::new(Box::pin(response_stream)),
+            echoed_metadata,
+        ))
+    }
+
+    /// Implements `EchoRepeat` for any request and response types that convert from and to
+    /// `echopb`. Sends `count` responses, waiting `interval_ms` between them. The `x-echo-*`
+    /// request headers apply to every response.
+    pub fn handle_echo_repeat<Req, Resp>(
+        &self,
+        request: Request<Req>,
+    ) -> Result<Response<EchoBiDirStream<Resp>>, Status>
+    where
+        Req: Into<EchoRepeatRequest>,
+        Resp: From<EchoResponse> + Send + 'static,
+    {
+        let receive_time = timestamp_now();
+        let request = request.map(Into::into);
+        println!("echo_repeat request.msg={:?}", request.get_ref());
+        if let Some(peer_identity) = PeerIdentity::from_request(&request) {
+            println!("echo_repeat peer_identity: {peer_identity}");
+        }
+        let controls = EchoControls::from_metadata(request.metadata())?;
+        if controls != EchoControls::default() {
+            println!("echo_repeat controls: {controls:?}");
+        }
+        let echoed_metadata = echoed_metadata(request.metadata())?;
+        if request.get_ref().count > MAX_REPEAT_COUNT {
+            return Err(Status::invalid_argument(format!(
+                "count={} must be at most {MAX_REPEAT_COUNT}",
+                request.get_ref().count
+            )));
+        }
+        if let Some(status) = self.injected_error("echo_repeat") {
+            return Err(status);
+        }
+        self.check_requested_error(&controls)?;
+        // every response has the same output, so the largest has the largest sequence
+        check_response_size(&EchoResponse {
+            output: controls.output(&request.get_ref().input),
+            sequence: u64::from(request.get_ref().count),
+            ..EchoResponse::default()
+        })?;
+        let mut rpc_guard = self.shutdown.start_rpc();
+        let shutdown = self.shutdown.clone();
+        let faults = self.faults.clone();
+        let request = request.into_inner();
+        let (response_stream_sender, response_stream_rx) =
+            tokio::sync::mpsc::channel(self.stream_channel_capacity.get());
+        let mut response_stream_sender = TimedSender::new(response_stream_sender);
+
+        tokio::spawn(async move {
+            do_echo_repeat(
+                &request,
+                receive_time,
+                &mut response_stream_sender,
+                &shutdown,
+                &mut rpc_guard,
+                faults.as_deref(),
+                controls,
+            )
+            .await;
+            println!(
+                "{} echo_repeat stream stats: {}",
+                now_formatted(),
+                response_stream_sender.stats()
+            );
+        });
+
+        let response_stream = ReceiverStream::new(response_stream_rx)
+            .map(|response_result| response_result.map(Resp::from));
+        Ok(echo_metadata(
+            Response::new(Box::pin(response_stream)),
+            echoed_metadata,
+        ))
+    }
+
+    /// Implements `EchoCollect` for any request and response types that convert from and to
+    /// `echopb`. The request stream is usually [`Streaming`]. Returns every input, and the
+    /// inputs joined by spaces as the output, which the `x-echo-*` request headers resize. Fails
+    /// with `RESOURCE_EXHAUSTED` after [`MAX_COLLECT_COUNT`] requests or
+    /// [`MAX_COLLECT_INPUT_BYTES`] of inputs.
+    pub async fn handle_echo_collect<S, Req, Resp>(
+        &self,
+        request: Request<S>,
+    ) -> Result<Response<Resp>, Status>
+    where
+        S: Stream<Item = Result<Req, Status>> + Send + 'static,
+        Req: Into<EchoRequest>,
+        Resp: From<EchoCollectResponse>,
+    {
+        let mut rpc_guard = self.shutdown.start_rpc();
+        println!("echo_collect: starting new echo_collect stream ...");
+        if let Some(peer_identity) = PeerIdentity::from_request(&request) {
+            println!("echo_collect peer_identity: {peer_identity}");
+        }
+        let controls = EchoControls::from_metadata(request.metadata())?;
+        if controls != EchoControls::default() {
+            println!("echo_collect controls: {controls:?}");
+        }
+        let echoed_metadata = echoed_metadata(request.metadata())?;
+        if let Some(status) = self.injected_error("echo_collect") {
+            return Err(status);
+        }
+
+        let request_stream = request.into_inner();
+        tokio::pin!(request_stream);
+        let mut inputs = Vec::new();
+        let mut input_bytes = 0;
+        loop {
+            let message = tokio::select! {
+                message = request_stream.next() => message.transpose()?,
+                () = self.shutdown.aborting() => {
+                    println!(
+                        "{} echo_collect aborting stream: shutdown grace period expired",
+                        now_formatted()
+                    );
+                    rpc_guard.abort();
+                    return Err(Status::unavailable("server is shutting down"));
+                }
+            };
+            let Some(request) = message else {
+                break;
+            };
+            let request: EchoRequest = request.into();
+            println!(
+                "{} echo_collect received request.input={:?}",
+                now_formatted(),
+                request.input
+            );
+            input_bytes += request.input.len();
+            if inputs.len() >= MAX_COLLECT_COUNT || input_bytes > MAX_COLLECT_INPUT_BYTES {
+                return Err(Status::resource_exhausted(format!(
+                    "echo_collect accepts at most {MAX_COLLECT_COUNT} requests and \
+                     {MAX_COLLECT_INPUT_BYTES} input bytes"
+                )));
+            }
+            inputs.push(request.input);
+        }
+
+        let mut wait = controls.delay.unwrap_or_default();
+        if let Some(latency) = self.faults.as_ref().and_then(|faults| faults.latency()) {
+            println!("echo_collect injecting latency: {latency:?}");
+            wait += latency;
+        }
+        sleep_unless_aborting("echo_collect", wait, &self.shutdown, &mut rpc_guard).await?;
+        self.check_requested_error(&controls)?;
+
+        let response = EchoCollectResponse {
+            count: u32::try_from(inputs.len()).unwrap_or(u32::MAX),
+            output: controls.output(&inputs.join(" ")),
+            inputs,
+        };
+        check_response_size(&response)?;
+        Ok(echo_metadata(
+            Response::new(response.into()),
+            echoed_metadata,
+        ))
+    }
+}
+
+/// Copies `metadata` to the headers of `response`, and to its trailers if the server is wrapped
+/// with [`crate::echo_metadata::ResponseTrailers`].
+fn echo_metadata<T>(mut response: Response<T>, metadata: Option<MetadataMap>) -> Response<T> {
+    if let Some(metadata) = metadata {
+        response
+            .extensions_mut()
+            .insert(TrailerMetadata(metadata.clone()));
+        *response.metadata_mut() = metadata;
+    }
+    response
+}
+
+/// Returns the current time as a protobuf `Timestamp`.
+fn timestamp_now() -> prost_types::Timestamp {
+    SystemTime::now().into()
+}
+
+/// Sets the server times of `response`: `receive_time`, and now as the send time.
+fn with_server_times(
+    mut response: EchoResponse,
+    receive_time: prost_types::Timestamp,
+) -> EchoResponse {
+    response.server_receive_time = Some(receive_time);
+    response.server_send_time = Some(timestamp_now());
+    response
+}
+
+/// Returns `request.delay`, or `INVALID_ARGUMENT` if it is negative or over [`MAX_DELAY`].
+fn requested_delay(request: &EchoRequest) -> Result<Option<Duration>, Status> {
+    request
+        .delay
+        .map(|delay| {
+            let duration = Duration::try_from(delay)
+                .map_err(|err| Status::invalid_argument(format!("invalid delay={delay}: {err}")))?;
+            if duration > MAX_DELAY {
+                return Err(Status::invalid_argument(format!(
+                    "delay={delay} must be at most {MAX_DELAY:?}"
+                )));
+            }
+            Ok(duration)
+        })
+        .transpose()
+}
+
+/// Waits for `delay`. If the server shutdown grace period expires first, marks `rpc_guard` as
+/// aborted and returns `UNAVAILABLE`.
+async fn sleep_unless_aborting(
+    rpc_name: &str,
+    delay: Duration,
+    shutdown: &Shutdown,
+    rpc_guard: &mut RpcGuard,
+) -> Result<(), Status> {
+    tokio::select! {
+        () = tokio::time::sleep(delay) => Ok(()),
+        () = shutdown.aborting() => {
+            println!(
+                "{} {rpc_name} aborting delay: shutdown grace period expired",
+                now_formatted()
+            );
+            rpc_guard.abort();
+            Err(Status::unavailable("server is shutting down"))
+        }
+    }
+}
+
+/// Prints the time from when the client sent `request` to now, if the client set it. The clocks of
+/// the client and server must be synchronized for this to be accurate.
+fn log_one_way_latency(rpc_name: &str, request: &EchoRequest) {
+    if request.send_time_unix_nanos == 0 {
+        return;
+    }
+    let latency_micros = unix_nanos_now().saturating_sub(request.send_time_unix_nanos) / 1000;
+    println!(
+        "{rpc_name} sequence={} one_way_latency_us={latency_micros}",
+        request.sequence
+    );
+}
+
+/// Prints the details attached to `status`.
+fn log_details(status: &Status) {
+    let details = match decode_details(status) {
+        Ok(details) => details,
+        Err(err) => {
+            eprintln!("failed decoding returned error details: {err}");
+            return;
+        }
+    };
+    for (i, detail) in details.iter().enumerate() {
+        println!(
+            "returning error details i={i} {}",
+            ERROR_DETAILS_REGISTRY.decode(detail)
+        );
+    }
+}
+
+/// Echoes each message on `request_stream`. If the server shutdown grace period expires, ends
+/// the stream with an `UNAVAILABLE` status and marks `rpc_guard` as aborted. Delays responses
+/// and cuts off the stream as chosen by `faults`, and delays and resizes responses as requested
+/// by `controls`.
+async fn do_echo_bi_dir<Req: Into<EchoRequest>>(
+    request_stream: impl Stream<Item = Result<Req, Status>>,
+    response_stream_sender: &mut TimedSender<Result<EchoResponse, tonic::Status>>,
+    shutdown: &Shutdown,
+    rpc_guard: &mut RpcGuard,
+    faults: Option<&FaultInjector>,
+    controls: EchoControls,
+) -> Result<(), tonic::Status> {
+    tokio::pin!(request_stream);
+    let stream_cutoff = faults.and_then(FaultInjector::stream_cutoff);
+    let mut responses_sent = 0;
+    loop {
+        if let (Some(faults), Some(cutoff)) = (faults, stream_cutoff)
+            && responses_sent >= cutoff
+        {
+            println!(
+                "{} echo_bi_dir injecting stream cutoff after {responses_sent} messages",
+                now_formatted()
+            );
+            send_response(
+                response_stream_sender,
+                Err(faults.stream_cutoff_status(responses_sent)),
+                shutdown,
+                rpc_guard,
+            )
+            .await?;
+            return Ok(());
+        }
+
+        let message = tokio::select! {
+            message = request_stream.next() => message.transpose()?,
+            () = shutdown.aborting() => {
+                println!(
+                    "{} echo_bi_dir aborting stream: shutdown grace period expired",
+                    now_formatted()
+                );
+                rpc_guard.abort();
+                send_response(
+                    response_stream_sender,
+                    Err(tonic::Status::unavailable("server is shutting down")),
+                    shutdown,
+                    rpc_guard,
+                )
+                .await?;
+                return Ok(());
+            }
+        };
+        let Some(request) = message else {
+            break;
+        };
+        let receive_time = timestamp_now();
+        let request: EchoRequest = request.into();
+        println!(
+            "{} echo_bi_dir received request.input={:?}",
+            now_formatted(),
+            request.input
+        );
+
+        let request_delay = requested_delay(&request);
+        let mut wait = controls.delay.unwrap_or_default()
+            + request_delay
+                .as_ref()
+                .ok()
+                .copied()
+                .flatten()
+                .unwrap_or_default();
+        if let Some(latency) = faults.and_then(FaultInjector::latency) {
+            println!(
+                "{} echo_bi_dir injecting latency: {latency:?}",
+                now_formatted()
+            );
+            wait += latency;
+        }
+        if let Err(status) = sleep_unless_aborting("echo_bi_dir", wait, shutdown, rpc_guard).await {
+            send_response(response_stream_sender, Err(status), shutdown, rpc_guard).await?;
+            return Ok(());
+        }
+
+        log_one_way_latency("echo_bi_dir", &request);
+        let response = request_delay
+            .and_then(|_| controls.response(&request))
+            .map(|response| with_server_times(response, receive_time));
+        // an invalid request ends the stream with its error
+        let is_err = response.is_err();
+        send_response(response_stream_sender, response, shutdown, rpc_guard).await?;
+        if is_err {
+            return Ok(());
+        }
+        responses_sent += 1;
+    }
+    let extra_message = EchoResponse {
+        output: "extra message after sender closed abcdef".to_string(),
+        ..EchoResponse::default()
+    };
+    println!(
+        "{} echo_bi_dir request stream ended; sending extra bonus message: {}",
+        now_formatted(),
+        extra_message.output
+    );
+    send_response(
+        response_stream_sender,
+        Ok(extra_message),
+        shutdown,
+        rpc_guard,
+    )
+    .await
+}
+
+/// Sends `response` to the caller, waiting while the channel is full. If the server shutdown grace
+/// period expires before a slow caller reads it, gives up, marks `rpc_guard` as aborted, and
+/// returns `UNAVAILABLE`. Returns `INTERNAL` if the caller went away.
+async fn send_response(
+    response_stream_sender: &mut TimedSender<Result<EchoResponse, tonic::Status>>,
+    response: Result<EchoResponse, tonic::Status>,
+    shutdown: &Shutdown,
+    rpc_guard: &mut RpcGuard,
+) -> Result<(), tonic::Status> {
+    tokio::select! {
+        // a response that fits in the channel is sent even while aborting
+        biased;
+        send_result = response_stream_sender.send(response) => send_result.map_err(|err| {
+            tonic::Status::internal(format!("response_stream_sender.send() failed: {err}"))
+        }),
+        () = shutdown.aborting() => {
+            rpc_guard.abort();
+            Err(tonic::Status::unavailable(
+                "server is shutting down: caller did not read the response",
+            ))
+        }
+    }
+}
+
+/// Returns the name other members of a room see for the sender of `request`: the subject of its
+/// client certificate, or its address.
+fn member_name<T>(request: &Request<T>) -> String {
+    if let Some(peer_identity) = PeerIdentity::from_request(request) {
+        return peer_identity.subject;
+    }
+    request
+        .remote_addr()
+        .map_or_else(|| "unknown".to_string(), |addr| addr.to_string())
+}
+
+/// Sends each message on `request_stream` to the room of `membership`, and sends every event in
+/// the room to the caller, until the caller closes the request stream. Leaves the room when it
+/// returns. If the server shutdown grace period expires, ends the stream with an `UNAVAILABLE`
+/// status and marks `rpc_guard` as aborted.
+async fn do_room_bi_dir<Req: Into<EchoRequest>>(
+    request_stream: impl Stream<Item = Result<Req, Status>>,
+    mut membership: RoomMembership,
+    response_stream_sender: &mut TimedSender<Result<EchoResponse, tonic::Status>>,
+    shutdown: &Shutdown,
+    rpc_guard: &mut RpcGuard,
+) -> Result<(), tonic::Status> {
+    tokio::pin!(request_stream);
+    loop {
+        let response = tokio::select! {
+            message = request_stream.next() => {
+                let Some(request) = message.transpose()? else {
+                    break;
+                };
+                let request: EchoRequest = request.into();
+                println!(
+                    "{} room {:?} received request.input={:?}",
+                    now_formatted(),
+                    membership.room(),
+                    request.input
+                );
+                membership.publish(&request);
+                continue;
+            }
+            event = membership.next() => {
+                if let RoomEvent::Lagged(skipped) = event {
+                    println!(
+                        "{} room {:?} member lagged: skipped {skipped} messages",
+                        now_formatted(),
+                        membership.room()
+                    );
+                }
+                Ok(event.response(membership.room()))
+            }
+            () = shutdown.aborting() => {
+                println!(
+                    "{} room {:?} aborting stream: shutdown grace period expired",
+                    now_formatted(),
+                    membership.room()
+                );
+                rpc_guard.abort();
+                Err(tonic::Status::unavailable("server is shutting down"))
+            }
+        };
+        let is_err = response.is_err();
+        send_response(response_stream_sender, response, shutdown, rpc_guard).await?;
+        if is_err {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// Sends the response to `request` `request.count` times. Stops early if the caller goes away or
+/// the server shutdown grace period expires. Delays responses and cuts off the stream as chosen
+/// by `faults`, and delays and resizes responses as requested by `controls`.
+async fn do_echo_repeat(
+    request: &EchoRepeatRequest,
+    receive_time: prost_types::Timestamp,
+    response_stream_sender: &mut TimedSender<Result<EchoResponse, tonic::Status>>,
+    shutdown: &Shutdown,
+    rpc_guard: &mut RpcGuard,
+    faults: Option<&FaultInjector>,
+    controls: EchoControls,
+) {
+    let interval = Duration::from_millis(request.interval_ms.into());
+    let stream_cutoff = faults.and_then(FaultInjector::stream_cutoff);
+    for i in 0..request.count {
+        let responses_sent = i as usize;
+        let mut wait = Duration::ZERO;
+        if i > 0 {
+            wait += interval;
+        }
+        if let Some(latency) = faults.and_then(FaultInjector::latency) {
+            println!(
+                "{} echo_repeat injecting latency: {latency:?}",
+                now_formatted()
+            );
+            wait += latency;
+        }
+        wait += controls.delay.unwrap_or_default();
+
+        let response = tokio::select! {
+            () = tokio::time::sleep(wait) => {
+                match (faults, stream_cutoff) {
+                    (Some(faults), Some(cutoff)) if responses_sent >= cutoff => {
+                        println!(
+                            "{} echo_repeat injecting stream cutoff after {responses_sent} messages",
+                            now_formatted()
+                        );
+                        Err(faults.stream_cutoff_status(responses_sent))
+                    }
+                    _ => Ok(with_server_times(
+                        EchoResponse {
+                            output: controls.output(&request.input),
+                            sequence: u64::from(i) + 1,
+                            ..EchoResponse::default()
+                        },
+                        receive_time,
+                    )),
+                }
+            }
+            () = shutdown.aborting() => {
+                println!(
+                    "{} echo_repeat aborting stream: shutdown grace period expired",
+                    now_formatted()
+                );
+                rpc_guard.abort();
+                Err(tonic::Status::unavailable("server is shutting down"))
+            }
+        };
+        let is_last = response.is_err();
+        if let Err(err) = send_response(response_stream_sender, response, shutdown, rpc_guard).await
+        {
+            println!(
+                "{} echo_repeat stopped after {responses_sent} messages: {err}",
+                now_formatted()
+            );
+            return;
+        }
+        if is_last {
+            return;
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl echopb::echo_server::Echo for EchoService {
+    async fn echo(&self, request: Request<EchoRequest>) -> Result<Response<EchoResponse>, Status> {
+        self.handle_echo(request).await
+    }
+
+    type EchoBiDirStream = EchoBiDirStream<EchoResponse>;
+
+    async fn echo_bi_dir(
+        &self,
+        request: Request<Streaming<EchoRequest>>,
+    ) -> Result<Response<Self::EchoBiDirStream>, Status> {
+        self.handle_echo_bi_dir(request)
+    }
+
+    type EchoRepeatStream = EchoBiDirStream<EchoResponse>;
+
+    async fn echo_repeat(
+        &self,
+        request: Request<EchoRepeatRequest>,
+    ) -> Result<Response<Self::EchoRepeatStream>, Status> {
+        self.handle_echo_repeat(request)
+    }
+
+    async fn echo_collect(
+        &self,
+        request: Request<Streaming<EchoRequest>>,
+    ) -> Result<Response<EchoCollectResponse>, Status> {
+        self.handle_echo_collect(request).await
+    }
+}
+
+#[tonic::async_trait]
+impl custom_codec_echopb::echo_server::Echo for EchoService {
+    async fn echo(
+        &self,
+        request: Request<custom_codec_echopb::EchoRequest>,
+    ) -> Result<Response<custom_codec_echopb::EchoResponse>, Status> {
+        self.handle_echo(request).await
+    }
+
+    type EchoBiDirStream = EchoBiDirStream<custom_codec_echopb::EchoResponse>;
+
+    async fn echo_bi_dir(
+        &self,
+        request: Request<Streaming<custom_codec_echopb::EchoRequest>>,
+    ) -> Result<Response<Self::EchoBiDirStream>, Status> {
+        self.handle_echo_bi_dir(request)
+    }
+
+    type EchoRepeatStream = EchoBiDirStream<custom_codec_echopb::EchoResponse>;
+
+    async fn echo_repeat(
+        &self,
+        request: Request<custom_codec_echopb::EchoRepeatRequest>,
+    ) -> Result<Response<Self::EchoRepeatStream>, Status> {
+        self.handle_echo_repeat(request)
+    }
+
+    async fn echo_collect(
+        &self,
+        request: Request<Streaming<custom_codec_echopb::EchoRequest>>,
+    ) -> Result<Response<custom_codec_echopb::EchoCollectResponse>, Status> {
+        self.handle_echo_collect(request).await
+    }
+}
+
+#[tonic::async_trait]
+impl json_echopb::echo_server::Echo for EchoService {
+    async fn echo(&self, request: Request<EchoRequest>) -> Result<Response<EchoResponse>, Status> {
+        self.handle_echo(request).await
+    }
+
+    type EchoBiDirStream = EchoBiDirStream<EchoResponse>;
+
+    async fn echo_bi_dir(
+        &self,
+        request: Request<Streaming<EchoRequest>>,
+    ) -> Result<Response<Self::EchoBiDirStream>, Status> {
+        self.handle_echo_bi_dir(request)
+    }
+
+    type EchoRepeatStream = EchoBiDirStream<EchoResponse>;
+
+    async fn echo_repeat(
+        &self,
+        request: Request<EchoRepeatRequest>,
+    ) -> Result<Response<Self::EchoRepeatStream>, Status> {
+        self.handle_echo_repeat(request)
+    }
+
+    async fn echo_collect(
+        &self,
+        request: Request<Streaming<EchoRequest>>,
+    ) -> Result<Response<EchoCollectResponse>, Status> {
+        self.handle_echo_collect(request).await
+    }
+}
+
+#[tonic::async_trait]
+impl json_codec_echopb::echo_server::Echo for EchoService {
+    async fn echo(&self, request: Request<EchoRequest>) -> Result<Response<EchoResponse>, Status> {
+        self.handle_echo(request).await
+    }
+
+    type EchoBiDirStream = EchoBiDirStream<EchoResponse>;
+
+    async fn echo_bi_dir(
+        &self,
+        request: Request<Streaming<EchoRequest>>,
+    ) -> Result<Response<Self::EchoBiDirStream>, Status> {
+        self.handle_echo_bi_dir(request)
+    }
+
+    type EchoRepeatStream = EchoBiDirStream<EchoResponse>;
+
+    async fn echo_repeat(
+        &self,
+        request: Request<EchoRepeatRequest>,
+    ) -> Result<Response<Self::EchoRepeatStream>, Status> {
+        self.handle_echo_repeat(request)
+    }
+
+    async fn echo_collect(
+        &self,
+        request: Request<Streaming<EchoRequest>>,
+    ) -> Result<Response<EchoCollectResponse>, Status> {
+        self.handle_echo_collect(request).await
+    }
+}
+
+#[tonic::async_trait]
+impl raw_codec_echopb::echo_server::Echo for EchoService {
+    async fn echo(&self, request: Request<Bytes>) -> Result<Response<Bytes>, Status> {
+        // keep the metadata and extensions, which contain the peer's TLS certificates
+        let (metadata, extensions, message) = request.into_parts();
+        let request =
+            Request::from_parts(metadata, extensions, decode_raw::<EchoRequest>(message)?);
+        let response = self.handle_echo(request).await?;
+        Ok(response.map(|response: EchoResponse| response.encode_to_vec().into()))
+    }
+
+    type EchoBiDirStream = EchoBiDirStream<Bytes>;
+
+    async fn echo_bi_dir(
+        &self,
+        request: Request<Streaming<Bytes>>,
+    ) -> Result<Response<Self::EchoBiDirStream>, Status> {
+        let request =
+            request.map(|stream| stream.map(|message| message.and_then(decode_raw::<EchoRequest>)));
+        let response = self.handle_echo_bi_dir(request)?;
+        Ok(response.map(|stream| -> Self::EchoBiDirStream {
+            Box::pin(stream.map(|message| {
+                message.map(|response: EchoResponse| response.encode_to_vec().into())
+            }))
+        }))
+    }
+
+    type EchoRepeatStream = EchoBiDirStream<Bytes>;
+
+    async fn echo_repeat(
+        &self,
+        request: Request<Bytes>,
+    ) -> Result<Response<Self::EchoRepeatStream>, Status> {
+        let (metadata, extensions, message) = request.into_parts();
+        let request = Request::from_parts(
+            metadata,
+            extensions,
+            decode_raw::<EchoRepeatRequest>(message)?,
+        );
+        let response = self.handle_echo_repeat(request)?;
+        Ok(response.map(|stream| -> Self::EchoRepeatStream {
+            Box::pin(stream.map(|message| {
+                message.map(|response: EchoResponse| response.encode_to_vec().into())
+            }))
+        }))
+    }
+
+    async fn echo_collect(
+        &self,
+        request: Request<Streaming<Bytes>>,
+    ) -> Result<Response<Bytes>, Status> {
+        let request =
+            request.map(|stream| stream.map(|message| message.and_then(decode_raw::<EchoRequest>)));
+        let response = self.handle_echo_collect(request).await?;
+        Ok(response.map(|response: EchoCollectResponse| response.encode_to_vec().into()))
+    }
+}
+
+/// Checks that `message` is a valid `M` before echoing it. Echoing needs the decoded request, so
+/// the raw service decodes and encodes every message like the other services.
+fn decode_raw<M: Message + Default + Name>(message: Bytes) -> Result<M, Status> {
+    println!("raw codec received {} bytes", message.len());
+    M::decode(message).map_err(|err| {
+        Status::invalid_argument(format!("request is not a valid {}: {err}", M::full_name()))
+    })
+}
+
+impl From<custom_codec_echopb::EchoRequest> for EchoRequest {
+    fn from(request: custom_codec_echopb::EchoRequest) -> Self {
+        let custom_codec_echopb::EchoRequest {
+            input,
+            payload,
+            response_size,
+            send_time_unix_nanos,
+            sequence,
+            delay,
+            attributes,
+        } = request;
+        Self {
+            input,
+            payload,
+            response_size,
+            send_time_unix_nanos,
+            sequence,
+            delay,
+            attributes,
+        }
+    }
+}
+
+impl From<EchoResponse> for custom_codec_echopb::EchoResponse {
+    fn from(response: EchoResponse) -> Self {
+        let EchoResponse {
+            output,
+            payload,
+            send_time_unix_nanos,
+            sequence,
+            server_receive_time,
+            server_send_time,
+            attributes,
+        } = response;
+        Self {
+            output,
+            payload,
+            send_time_unix_nanos,
+            sequence,
+            server_receive_time,
+            server_send_time,
+            attributes,
+        }
+    }
+}
+
+impl From<custom_codec_echopb::EchoRepeatRequest> for EchoRepeatRequest {
+    fn from(request: custom_codec_echopb::EchoRepeatRequest) -> Self {
+        let custom_codec_echopb::EchoRepeatRequest {
+            input,
+            count,
+            interval_ms,
+        } = request;
+        Self {
+            input,
+            count,
+            interval_ms,
+        }
+    }
+}
+
+impl From<EchoCollectResponse> for custom_codec_echopb::EchoCollectResponse {
+    fn from(response: EchoCollectResponse) -> Self {
+        let EchoCollectResponse {
+            count,
+            inputs,
+            output,
+        } = response;
+        Self {
+            count,
+            inputs,
+            output,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handle_echo_for_each_message_type() {
+        let echo_service = EchoService::new(false, Shutdown::new());
+        let response: Response<EchoResponse> = echo_service
+            .handle_echo(Request::new(EchoRequest {
+                input: "hello".to_string(),
+                ..EchoRequest::default()
+            }))
+            .await
+            .unwrap();
+        assert_eq!(response.get_ref().output, "echoed: hello");
+
+        let response: Response<custom_codec_echopb::EchoResponse> = echo_service
+            .handle_echo(Request::new(custom_codec_echopb::EchoRequest {
+                input: "hello".to_string(),
+                ..custom_codec_echopb::EchoRequest::default()
+            }))
+            .await
+            .unwrap();
+        assert_eq!(response.get_ref().output, "echoed: hello");
+    }
+
+    #[tokio::test]
+    async fn test_handle_echo_bi_dir_custom_codec() {
+        let echo_service = EchoService::new(false, Shutdown::new());
+        let request = |input: &str| {
+            Ok(custom_codec_echopb::EchoRequest {
+                input: input.to_string(),
+                ..custom_codec_echopb::EchoRequest::default()
+            })
+        };
+        let response_stream = echo_service
+            .handle_echo_bi_dir::<_, _, custom_codec_echopb::EchoResponse>(Request::new(
+                tokio_stream::iter([request("a"), request("b")]),
+            ))
+            .unwrap()
+            .into_inner();
+        let outputs = response_stream
+            .map(|response| response.unwrap().output)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            outputs,
+            [
+                "echoed: a",
+                "echoed: b",
+                "extra message after sender closed abcdef"
+            ]
+        );
+
+        // an error on the request stream is sent to the caller
+        let response_stream = echo_service
+            .handle_echo_bi_dir::<_, _, custom_codec_echopb::EchoResponse>(Request::new(
+                tokio_stream::iter([request("a"), Err(Status::data_loss("broken stream"))]),
+            ))
+            .unwrap()
+            .into_inner();
+        let responses = response_stream.collect::<Vec<_>>().await;
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].as_ref().unwrap().output, "echoed: a");
+        let status = responses[1].as_ref().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::DataLoss);
+        assert_eq!(status.message(), "broken stream");
+    }
+
+    #[tokio::test]
+    async fn test_handle_echo_well_known_types() {
+        let echo_service = EchoService::new(false, Shutdown::new());
+        let attributes = prost_types::Struct {
+            fields: [("key".to_string(), 1.5.into())].into(),
+        };
+        let before = timestamp_now();
+        let response: Response<EchoResponse> = echo_service
+            .handle_echo(Request::new(EchoRequest {
+                delay: Some(prost_types::Duration {
+                    seconds: 0,
+                    nanos: 10_000_000,
+                }),
+                attributes: Some(attributes.clone()),
+                ..EchoRequest::default()
+            }))
+            .await
+            .unwrap();
+        let response = response.into_inner();
+        assert_eq!(response.attributes, Some(attributes));
+        let receive_time = SystemTime::try_from(response.server_receive_time.unwrap()).unwrap();
+        let send_time = SystemTime::try_from(response.server_send_time.unwrap()).unwrap();
+        assert!(receive_time >= SystemTime::try_from(before).unwrap());
+        assert!(send_time.duration_since(receive_time).unwrap() >= Duration::from_millis(10));
+
+        let status = echo_service
+            .handle_echo::<_, EchoResponse>(Request::new(EchoRequest {
+                delay: Some(prost_types::Duration {
+                    seconds: -1,
+                    nanos: 0,
+                }),
+                ..EchoRequest::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let status = echo_service
+            .handle_echo::<_, EchoResponse>(Request::new(EchoRequest {
+                delay: Some(prost_types::Duration {
+                    seconds: i64::MAX,
+                    nanos: 0,
+                }),
+                ..EchoRequest::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_handle_echo_err_details() {
+        let echo_service = EchoService::new(true, Shutdown::new());
+        let status = echo_service
+            .handle_echo::<_, custom_codec_echopb::EchoResponse>(Request::new(
+                custom_codec_echopb::EchoRequest::default(),
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Internal);
+
+        let status_pb = tonic_types::Status::decode(status.details()).unwrap();
+        assert_eq!(status_pb.message, status.message());
+        assert_eq!(status_pb.details.len(), 2);
+        assert_eq!(
+            status_pb.details[0].to_msg::<Example1>().unwrap(),
+            Example1 { int64_value: 99 }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_echo_error_detail_types() {
+        use tonic_types::StatusExt;
+
+        let echo_service = EchoService::new(true, Shutdown::new()).with_error_detail_types(vec![
+            ErrorDetailType::RetryInfo,
+            ErrorDetailType::Example2,
+            ErrorDetailType::LocalizedMessage,
+        ]);
+        let status = echo_service
+            .handle_echo::<_, EchoResponse>(Request::new(EchoRequest::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.message(), "error with 3 details");
+        let error_details = status.check_error_details().unwrap();
+        assert!(error_details.retry_info().is_some());
+        assert_eq!(error_details.localized_message().unwrap().locale, "en-US");
+        let status_pb = tonic_types::Status::decode(status.details()).unwrap();
+        assert_eq!(
+            status_pb.details[1].to_msg::<Example2>().unwrap(),
+            Example2 {
+                float64_value: 1.234
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_echo_controls_override_flags() {
+        use crate::echo_controls::ERROR_DETAILS_HEADER;
+        use crate::echo_controls::RESPONSE_SIZE_HEADER;
+
+        let echo_service = EchoService::new(true, Shutdown::new());
+        let mut request = Request::new(EchoRequest {
+            input: "hello".to_string(),
+            ..EchoRequest::default()
+        });
+        request
+            .metadata_mut()
+            .insert(ERROR_DETAILS_HEADER, "false".parse().unwrap());
+        request
+            .metadata_mut()
+            .insert(RESPONSE_SIZE_HEADER, "20".parse().unwrap());
+        let response: Response<EchoResponse> = echo_service.handle_echo(request).await.unwrap();
+        assert_eq!(response.get_ref().output, "echoed: hello.......");
+
+        let mut request = Request::new(EchoRequest::default());
+        request
+            .metadata_mut()
+            .insert(STATUS_CODE_HEADER, "UNAVAILABLE".parse().unwrap());
+        let status = echo_service
+            .handle_echo::<_, EchoResponse>(request)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        let status_pb = tonic_types::Status::decode(status.details()).unwrap();
+        assert_eq!(status_pb.code, tonic::Code::Unavailable as i32);
+        assert_eq!(status_pb.details.len(), 2);
+
+        let echo_service = EchoService::new(false, Shutdown::new());
+        let mut request = Request::new(tokio_stream::iter([Ok(EchoRequest::default())]));
+        request
+            .metadata_mut()
+            .insert(STATUS_CODE_HEADER, "aborted".parse().unwrap());
+        let Err(status) = echo_service.handle_echo_bi_dir::<_, _, EchoResponse>(request) else {
+            panic!("expected echo_bi_dir to fail");
+        };
+        assert_eq!(status.code(), tonic::Code::Aborted);
+        assert!(status.details().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_handle_echo_repeat_and_collect() {
+        let echo_service = EchoService::new(false, Shutdown::new());
+        let response_stream = echo_service
+            .handle_echo_repeat::<_, EchoResponse>(Request::new(EchoRepeatRequest {
+                input: "hello".to_string(),
+                count: 3,
+                interval_ms: 1,
+            }))
+            .unwrap()
+            .into_inner();
+        let responses = response_stream
+            .map(|response| {
+                let response = response.unwrap();
+                (response.output, response.sequence)
+            })
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            responses,
+            [1, 2, 3].map(|sequence| ("echoed: hello".to_string(), sequence))
+        );
+
+        let status = echo_service
+            .handle_echo_repeat::<_, EchoResponse>(Request::new(EchoRepeatRequest {
+                count: MAX_REPEAT_COUNT + 1,
+                ..EchoRepeatRequest::default()
+            }))
+            .err()
+            .unwrap();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        // the padded output and the sequence number do not fit in one response
+        let mut request = Request::new(EchoRepeatRequest {
+            count: 1,
+            ..EchoRepeatRequest::default()
+        });
+        request.metadata_mut().insert(
+            crate::echo_controls::RESPONSE_SIZE_HEADER,
+            crate::echo_controls::MAX_RESPONSE_SIZE
+                .to_string()
+                .parse()
+                .unwrap(),
+        );
+        let status = echo_service
+            .handle_echo_repeat::<_, EchoResponse>(request)
+            .err()
+            .unwrap();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let requests = ["a", "b", "c"].map(|input| {
+            Ok(EchoRequest {
+                input: input.to_string(),
+                ..EchoRequest::default()
+            })
+        });
+        let response: Response<EchoCollectResponse> = echo_service
+            .handle_echo_collect(Request::new(tokio_stream::iter(requests)))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.into_inner(),
+            EchoCollectResponse {
+                count: 3,
+                inputs: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+                output: "echoed: a b c".to_string(),
+            }
+        );
+
+        let too_many = (0..=MAX_COLLECT_COUNT).map(|_| Ok(EchoRequest::default()));
+        let status = echo_service
+            .handle_echo_collect::<_, _, EchoCollectResponse>(Request::new(tokio_stream::iter(
+                too_many,
+            )))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+
+        let too_large = ["a", "b"].map(|_| {
+            Ok(EchoRequest {
+                input: "x".repeat(MAX_COLLECT_INPUT_BYTES / 2 + 1),
+                ..EchoRequest::default()
+            })
+        });
+        let status = echo_service
+            .handle_echo_collect::<_, _, EchoCollectResponse>(Request::new(tokio_stream::iter(
+                too_large,
+            )))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    }
+
+    #[tokio::test]
+    async fn test_abort_stream_blocked_on_slow_caller() {
+        let shutdown = Shutdown::new();
+        let echo_service = EchoService::new(false, shutdown.clone());
+        // the caller never reads, so the server waits for space in the response channel
+        let response_stream = echo_service
+            .handle_echo_repeat::<_, EchoResponse>(Request::new(EchoRepeatRequest {
+                input: "hello".to_string(),
+                count: MAX_REPEAT_COUNT,
+                interval_ms: 0,
+            }))
+            .unwrap()
+            .into_inner();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(shutdown.summary().active, 1);
+
+        shutdown.start_draining();
+        shutdown.start_aborting();
+        tokio::time::timeout(Duration::from_secs(1), shutdown.idle())
+            .await
+            .unwrap();
+        assert_eq!(shutdown.summary().aborted, 1);
+        drop(response_stream);
+    }
+
+    #[tokio::test]
+    async fn test_abort_echo_collect() {
+        let shutdown = Shutdown::new();
+        let echo_service = EchoService::new(false, shutdown.clone());
+        // the caller never closes the request stream
+        let (_request_sender, request_receiver) =
+            tokio::sync::mpsc::channel::<Result<EchoRequest, Status>>(1);
+        let collect = echo_service.handle_echo_collect::<_, _, EchoCollectResponse>(Request::new(
+            ReceiverStream::new(request_receiver),
+        ));
+        let abort = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            assert_eq!(shutdown.summary().active, 1);
+            shutdown.start_draining();
+            shutdown.start_aborting();
+        };
+        let (result, ()) = tokio::time::timeout(Duration::from_secs(1), async {
+            tokio::join!(collect, abort)
+        })
+        .await
+        .unwrap();
+        assert_eq!(result.unwrap_err().code(), tonic::Code::Unavailable);
+        assert_eq!(shutdown.summary().aborted, 1);
+        assert_eq!(shutdown.summary().drained, 0);
+    }
+
+    #[tokio::test]
+    async fn test_abort_delayed_echo() {
+        let shutdown = Shutdown::new();
+        let echo_service = EchoService::new(false, shutdown.clone());
+        let echo = echo_service.handle_echo::<_, EchoResponse>(Request::new(EchoRequest {
+            delay: Some(MAX_DELAY.try_into().unwrap()),
+            ..EchoRequest::default()
+        }));
+        let abort = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            assert_eq!(shutdown.summary().active, 1);
+            shutdown.start_draining();
+            shutdown.start_aborting();
+        };
+        let (result, ()) =
+            tokio::time::timeout(Duration::from_secs(1), async { tokio::join!(echo, abort) })
+                .await
+                .unwrap();
+        assert_eq!(result.unwrap_err().code(), tonic::Code::Unavailable);
+        assert_eq!(shutdown.summary().aborted, 1);
+    }
+
+    #[tokio::test]
+    async fn test_echo_bi_dir_room_broadcast() {
+        use crate::chat_room::ROOM_HEADER;
+
+        let echo_service = EchoService::new(false, Shutdown::new());
+        let join = |room: &str| {
+            let (request_sender, request_receiver) = tokio::sync::mpsc::channel(1);
+            let mut request = Request::new(ReceiverStream::new(request_receiver));
+            request
+                .metadata_mut()
+                .insert(ROOM_HEADER, room.parse().unwrap());
+            let response_stream = echo_service
+                .handle_echo_bi_dir::<_, _, EchoResponse>(request)
+                .unwrap()
+                .into_inner();
+            (request_sender, response_stream)
+        };
+        let (alice_sender, mut alice_responses) = join("lobby");
+        let (bob_sender, mut bob_responses) = join("lobby");
+        let (_carol_sender, mut carol_responses) = join("other");
+
+        alice_sender
+            .send(Ok(EchoRequest {
+                input: "hello".to_string(),
+                sequence: 7,
+                ..EchoRequest::default()
+            }))
+            .await
+            .unwrap();
+        for responses in [&mut alice_responses, &mut bob_responses] {
+            let response = responses.next().await.unwrap().unwrap();
+            assert_eq!(response.output, "unknown: hello");
+            assert_eq!(response.sequence, 7);
+            assert!(response.server_receive_time.is_some());
+        }
+
+        // closing the request stream leaves the room and ends the response stream
+        drop(alice_sender);
+        assert!(alice_responses.next().await.is_none());
+        bob_sender
+            .send(Ok(EchoRequest {
+                input: "anyone?".to_string(),
+                ..EchoRequest::default()
+            }))
+            .await
+            .unwrap();
+        let response = bob_responses.next().await.unwrap().unwrap();
+        assert_eq!(response.output, "unknown: anyone?");
+        assert_eq!(echo_service.rooms.member_count("lobby"), 1);
+
+        let no_message =
+            tokio::time::timeout(Duration::from_millis(10), carol_responses.next()).await;
+        assert!(no_message.is_err(), "{no_message:?}");
+    }
+
+    #[tokio::test]
+    async fn test_injected_faults() {
+        use crate::fault::FaultPolicy;
+
+        let echo_service =
+            EchoService::new(false, Shutdown::new()).with_faults(FaultInjector::new(FaultPolicy {
+                error_percent: 100,
+                error_code: tonic::Code::ResourceExhausted,
+                ..FaultPolicy::default()
+            }));
+        let status = echo_service
+            .handle_echo::<_, EchoResponse>(Request::new(EchoRequest::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+
+        let echo_service =
+            EchoService::new(false, Shutdown::new()).with_faults(FaultInjector::new(FaultPolicy {
+                error_code: tonic::Code::Aborted,
+                stream_cutoff_percent: 100,
+                stream_cutoff_messages: 2,
+                ..FaultPolicy::default()
+            }));
+        let requests = ["a", "b", "c"].map(|input| {
+            Ok(EchoRequest {
+                input: input.to_string(),
+                ..EchoRequest::default()
+            })
+        });
+        let response_stream = echo_service
+            .handle_echo_bi_dir::<_, _, EchoResponse>(Request::new(tokio_stream::iter(requests)))
+            .unwrap()
+            .into_inner();
+        let responses = response_stream.collect::<Vec<_>>().await;
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[1].as_ref().unwrap().output, "echoed: b");
+        assert_eq!(
+            responses[2].as_ref().unwrap_err().code(),
+            tonic::Code::Aborted
+        );
+    }
+}
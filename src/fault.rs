@@ -0,0 +1,345 @@
+//! Fault injection for resilience testing.
+//!
+//! A [`FaultPolicy`] makes a percentage of RPCs fail, respond slowly, or end their streams early.
+//! [`FaultInjector`] makes the random choices with a seeded generator, so the same seed injects
+//! the same faults into the same sequence of RPCs.
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::time::Duration;
+
+use rand::Rng;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use serde::Deserialize;
+use tonic::Code;
+use tonic::Status;
+
+/// The names of the gRPC status codes, indexed by code.
+const CODE_NAMES: [&str; 17] = [
+    "OK",
+    "CANCELLED",
+    "UNKNOWN",
+    "INVALID_ARGUMENT",
+    "DEADLINE_EXCEEDED",
+    "NOT_FOUND",
+    "ALREADY_EXISTS",
+    "PERMISSION_DENIED",
+    "RESOURCE_EXHAUSTED",
+    "FAILED_PRECONDITION",
+    "ABORTED",
+    "OUT_OF_RANGE",
+    "UNIMPLEMENTED",
+    "INTERNAL",
+    "UNAVAILABLE",
+    "DATA_LOSS",
+    "UNAUTHENTICATED",
+];
+
+/// Parses a gRPC status code from its name (e.g. `UNAVAILABLE`) or number (e.g. `14`).
+pub fn parse_code(s: &str) -> Result<Code, String> {
+    let code_number = CODE_NAMES
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case(s))
+        .and_then(|index| i32::try_from(index).ok())
+        .or_else(|| s.parse().ok())
+        .ok_or_else(|| format!("invalid gRPC status code: {s:?}"))?;
+    if !(0..=16).contains(&code_number) {
+        return Err(format!("invalid gRPC status code: {s:?}"));
+    }
+    Ok(Code::from_i32(code_number))
+}
+
+/// Parses a status code like [`parse_code`], but rejects `OK`: a failed RPC with `OK` reaches
+/// clients as a response without a message.
+pub fn parse_error_code(s: &str) -> Result<Code, String> {
+    let code = parse_code(s)?;
+    if code == Code::Ok {
+        return Err(format!("invalid error code: {s:?} is not an error"));
+    }
+    Ok(code)
+}
+
+fn deserialize_error_code<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Code, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_error_code(&s).map_err(serde::de::Error::custom)
+}
+
+/// The faults to inject, and how often. Percentages are from 0 to 100.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FaultPolicy {
+    /// Seeds the random choices.
+    pub seed: u64,
+    /// Percentage of RPCs that fail with `error_code` before doing anything.
+    pub error_percent: u32,
+    #[serde(deserialize_with = "deserialize_error_code")]
+    pub error_code: Code,
+    /// Percentage of responses that are delayed by `latency_ms`.
+    pub latency_percent: u32,
+    pub latency_ms: u64,
+    /// Percentage of `EchoBiDir` and `EchoRepeat` streams that fail with `error_code` after
+    /// sending `stream_cutoff_messages`.
+    pub stream_cutoff_percent: u32,
+    pub stream_cutoff_messages: usize,
+}
+
+impl Default for FaultPolicy {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            error_percent: 0,
+            error_code: Code::Unavailable,
+            latency_percent: 0,
+            latency_ms: 0,
+            stream_cutoff_percent: 0,
+            stream_cutoff_messages: 0,
+        }
+    }
+}
+
+impl FaultPolicy {
+    /// Parses a policy from JSON, for example `{"seed": 1, "error_percent": 10}`. Missing fields
+    /// have their default values.
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let policy: Self =
+            serde_json::from_str(json).map_err(|err| format!("invalid fault policy: {err}"))?;
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for (name, percent) in [
+            ("error_percent", self.error_percent),
+            ("latency_percent", self.latency_percent),
+            ("stream_cutoff_percent", self.stream_cutoff_percent),
+        ] {
+            if percent > 100 {
+                return Err(format!(
+                    "invalid fault policy: {name}={percent} must be at most 100"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if this policy injects any faults.
+    #[must_use]
+    pub const fn is_enabled(&self) -> bool {
+        self.error_percent > 0 || self.latency_percent > 0 || self.stream_cutoff_percent > 0
+    }
+}
+
+/// Command line arguments for the fault injection policy.
+#[derive(Debug, clap::Args)]
+#[expect(
+    clippy::struct_field_names,
+    reason = "the fields are the --fault-* flags"
+)]
+pub struct FaultArgs {
+    /// JSON file with the fault injection policy, with the same fields as the other `--fault-*`
+    /// flags. For example: `{"seed": 1, "error_percent": 10, "error_code": "UNAVAILABLE"}`.
+    #[clap(
+        long,
+        conflicts_with_all = [
+            "fault_seed",
+            "fault_error_percent",
+            "fault_error_code",
+            "fault_latency_percent",
+            "fault_latency_ms",
+            "fault_stream_cutoff_percent",
+            "fault_stream_cutoff_messages",
+        ]
+    )]
+    fault_config: Option<PathBuf>,
+
+    /// Seeds the random fault choices. The same seed injects the same faults into the same
+    /// sequence of RPCs.
+    #[clap(long, default_value_t = 0)]
+    fault_seed: u64,
+
+    /// Percentage of RPCs that fail with `--fault-error-code`.
+    #[clap(long, default_value_t = 0, value_parser = clap::value_parser!(u32).range(0..=100))]
+    fault_error_percent: u32,
+
+    /// The status code for injected errors, as a name like `UNAVAILABLE` or a number. Must not be
+    /// `OK`.
+    #[clap(long, default_value = "UNAVAILABLE", value_parser = parse_error_code)]
+    fault_error_code: Code,
+
+    /// Percentage of responses that are delayed by `--fault-latency-ms`.
+    #[clap(long, default_value_t = 0, value_parser = clap::value_parser!(u32).range(0..=100))]
+    fault_latency_percent: u32,
+
+    /// Extra latency for delayed responses.
+    #[clap(long, default_value_t = 0)]
+    fault_latency_ms: u64,
+
+    /// Percentage of `EchoBiDir` and `EchoRepeat` streams that fail with `--fault-error-code`
+    /// after sending `--fault-stream-cutoff-messages` responses.
+    #[clap(long, default_value_t = 0, value_parser = clap::value_parser!(u32).range(0..=100))]
+    fault_stream_cutoff_percent: u32,
+
+    /// Responses to send before cutting off a stream.
+    #[clap(long, default_value_t = 0)]
+    fault_stream_cutoff_messages: usize,
+}
+
+impl FaultArgs {
+    /// Returns the policy from `--fault-config` or the other flags, or `None` if it does not
+    /// inject any faults.
+    pub fn fault_policy(&self) -> Result<Option<FaultPolicy>, Box<dyn std::error::Error>> {
+        let policy = if let Some(path) = &self.fault_config {
+            FaultPolicy::from_json(&std::fs::read_to_string(path)?)?
+        } else {
+            FaultPolicy {
+                seed: self.fault_seed,
+                error_percent: self.fault_error_percent,
+                error_code: self.fault_error_code,
+                latency_percent: self.fault_latency_percent,
+                latency_ms: self.fault_latency_ms,
+                stream_cutoff_percent: self.fault_stream_cutoff_percent,
+                stream_cutoff_messages: self.fault_stream_cutoff_messages,
+            }
+        };
+        Ok(policy.is_enabled().then_some(policy))
+    }
+}
+
+/// Chooses which RPCs get the faults in a [`FaultPolicy`].
+#[derive(Debug)]
+pub struct FaultInjector {
+    policy: FaultPolicy,
+    rng: Mutex<StdRng>,
+}
+
+impl FaultInjector {
+    #[must_use]
+    pub fn new(policy: FaultPolicy) -> Self {
+        let rng = Mutex::new(StdRng::seed_from_u64(policy.seed));
+        Self { policy, rng }
+    }
+
+    /// Returns true `percent` percent of the time. Does not use a random number if `percent` is
+    /// 0, so disabled faults do not change the choices of the enabled faults.
+    fn chance(&self, percent: u32) -> bool {
+        if percent == 0 {
+            return false;
+        }
+        let mut rng = self.rng.lock().unwrap_or_else(PoisonError::into_inner);
+        rng.random_range(0..100) < percent
+    }
+
+    /// Returns the error to fail an RPC with, if it should fail.
+    pub fn error(&self) -> Option<Status> {
+        self.chance(self.policy.error_percent)
+            .then(|| Status::new(self.policy.error_code, "injected fault"))
+    }
+
+    /// Returns the extra latency for a response, if it should be delayed.
+    pub fn latency(&self) -> Option<Duration> {
+        self.chance(self.policy.latency_percent)
+            .then(|| Duration::from_millis(self.policy.latency_ms))
+    }
+
+    /// Returns the number of responses to send before cutting off a stream, if it should be cut
+    /// off.
+    pub fn stream_cutoff(&self) -> Option<usize> {
+        self.chance(self.policy.stream_cutoff_percent)
+            .then_some(self.policy.stream_cutoff_messages)
+    }
+
+    /// Returns the error that ends a stream that was cut off after `messages` responses.
+    #[must_use]
+    pub fn stream_cutoff_status(&self, messages: usize) -> Status {
+        Status::new(
+            self.policy.error_code,
+            format!("injected fault: stream cut off after {messages} messages"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_code() {
+        assert_eq!(parse_code("UNAVAILABLE"), Ok(Code::Unavailable));
+        assert_eq!(parse_code("not_found"), Ok(Code::NotFound));
+        assert_eq!(parse_code("13"), Ok(Code::Internal));
+        assert!(parse_code("17").is_err());
+        assert!(parse_code("NOT_A_CODE").is_err());
+
+        assert_eq!(parse_error_code("aborted"), Ok(Code::Aborted));
+        assert!(parse_error_code("OK").is_err());
+        assert!(parse_error_code("0").is_err());
+    }
+
+    #[test]
+    fn test_policy_from_json() {
+        let policy = FaultPolicy::from_json(
+            r#"{"seed": 7, "error_percent": 10, "error_code": "ABORTED", "latency_ms": 5}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            policy,
+            FaultPolicy {
+                seed: 7,
+                error_percent: 10,
+                error_code: Code::Aborted,
+                latency_ms: 5,
+                ..FaultPolicy::default()
+            }
+        );
+        assert!(policy.is_enabled());
+
+        assert!(FaultPolicy::from_json(r#"{"error_percent": 101}"#).is_err());
+        assert!(FaultPolicy::from_json(r#"{"error_precent": 10}"#).is_err());
+        assert!(FaultPolicy::from_json(r#"{"error_code": "OK"}"#).is_err());
+    }
+
+    #[test]
+    fn test_injector_is_reproducible() {
+        let policy = FaultPolicy {
+            seed: 42,
+            error_percent: 30,
+            latency_percent: 50,
+            latency_ms: 10,
+            ..FaultPolicy::default()
+        };
+        let choices = |injector: &FaultInjector| {
+            (0..100)
+                .map(|_| (injector.error().is_some(), injector.latency()))
+                .collect::<Vec<_>>()
+        };
+        let first = choices(&FaultInjector::new(policy.clone()));
+        assert_eq!(first, choices(&FaultInjector::new(policy)));
+
+        let errors = first.iter().filter(|(error, _)| *error).count();
+        assert!((10..50).contains(&errors), "errors={errors}");
+        assert!(first.iter().any(|(_, latency)| latency.is_none()));
+        assert!(
+            first
+                .iter()
+                .any(|(_, latency)| *latency == Some(Duration::from_millis(10)))
+        );
+    }
+
+    #[test]
+    fn test_injector_always_and_never() {
+        let injector = FaultInjector::new(FaultPolicy {
+            error_percent: 100,
+            error_code: Code::DataLoss,
+            stream_cutoff_percent: 0,
+            ..FaultPolicy::default()
+        });
+        for _ in 0..100 {
+            assert_eq!(injector.error().unwrap().code(), Code::DataLoss);
+            assert_eq!(injector.stream_cutoff(), None);
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! A codec that sends messages as JSON instead of protobuf, for clients without protobuf tooling.
+//!
+//! The `json.echopb.Echo` service generated by `build.rs` uses [`JsonCodec`]. Tonic always sets
+//! `content-type: application/grpc`, so servers should be wrapped with [`JsonContentType`] and
+//! clients should use [`json_channel`] to send `application/grpc+json` instead.
+
+use std::marker::PhantomData;
+use std::task::Context;
+use std::task::Poll;
+
+use bytes::Buf;
+use bytes::BufMut;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tonic::Status;
+use tonic::body::Body;
+use tonic::codec::Codec;
+use tonic::codec::DecodeBuf;
+use tonic::codec::Decoder;
+use tonic::codec::EncodeBuf;
+use tonic::codec::Encoder;
+use tonic::codegen::BoxFuture;
+use tonic::codegen::Service;
+use tonic::codegen::http;
+use tonic::server::NamedService;
+use tonic::transport::Channel;
+
+/// The content type for gRPC messages encoded as JSON.
+pub const JSON_CONTENT_TYPE: &str = "application/grpc+json";
+
+/// Encodes `T` and decodes `U` with `serde_json`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec<T, U>(PhantomData<(T, U)>);
+
+impl<T, U> Codec for JsonCodec<T, U>
+where
+    T: Serialize + Send + 'static,
+    U: DeserializeOwned + Send + 'static,
+{
+    type Encode = T;
+    type Decode = U;
+
+    type Encoder = JsonEncoder<T>;
+    type Decoder = JsonDecoder<U>;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        JsonEncoder(PhantomData)
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        JsonDecoder(PhantomData)
+    }
+}
+
+#[derive(Debug)]
+pub struct JsonEncoder<T>(PhantomData<T>);
+
+impl<T: Serialize> Encoder for JsonEncoder<T> {
+    type Item = T;
+    type Error = Status;
+
+    fn encode(&mut self, item: Self::Item, buf: &mut EncodeBuf<'_>) -> Result<(), Self::Error> {
+        serde_json::to_writer(buf.writer(), &item)
+            .map_err(|err| Status::internal(format!("failed encoding JSON message: {err}")))
+    }
+}
+
+#[derive(Debug)]
+pub struct JsonDecoder<U>(PhantomData<U>);
+
+impl<U: DeserializeOwned> Decoder for JsonDecoder<U> {
+    type Item = U;
+    type Error = Status;
+
+    fn decode(&mut self, buf: &mut DecodeBuf<'_>) -> Result<Option<Self::Item>, Self::Error> {
+        // every JSON value has at least one byte
+        if !buf.has_remaining() {
+            return Ok(None);
+        }
+        let item = serde_json::from_reader(buf.reader())
+            .map_err(|err| Status::internal(format!("failed decoding JSON message: {err}")))?;
+        Ok(Some(item))
+    }
+}
+
+/// Wraps a server to set `content-type: application/grpc+json` on its responses.
+#[derive(Debug, Clone)]
+pub struct JsonContentType<S> {
+    inner: S,
+}
+
+impl<S> JsonContentType<S> {
+    #[must_use]
+    pub const fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S: NamedService> NamedService for JsonContentType<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for JsonContentType<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        let future = self.inner.call(request);
+        Box::pin(async move {
+            let mut response = future.await?;
+            set_json_content_type(response.headers_mut());
+            Ok(response)
+        })
+    }
+}
+
+/// A channel that sends requests with `content-type: application/grpc+json`.
+pub type JsonChannel =
+    tower::util::MapRequest<Channel, fn(http::Request<Body>) -> http::Request<Body>>;
+
+/// Returns `channel` wrapped to send `content-type: application/grpc+json`, for clients of
+/// services that use [`JsonCodec`].
+#[must_use]
+pub fn json_channel(channel: Channel) -> JsonChannel {
+    tower::util::MapRequest::new(channel, |mut request| {
+        set_json_content_type(request.headers_mut());
+        request
+    })
+}
+
+fn set_json_content_type(headers: &mut http::HeaderMap) {
+    headers.insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static(JSON_CONTENT_TYPE),
+    );
+}
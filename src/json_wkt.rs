@@ -0,0 +1,262 @@
+//! Serializes the protobuf well-known types with serde, using their protobuf JSON mapping.
+//!
+//! [`JsonCodec`](crate::json_codec::JsonCodec) serializes the `echopb` messages with serde, but
+//! prost-types does not implement serde for `Timestamp`, `Duration` or `Struct`, and serde's
+//! defaults for `bytes` and 64-bit integer fields are not their protobuf JSON forms. `build.rs`
+//! attaches these modules to those fields with `#[serde(with = ...)]`. See
+//! <https://protobuf.dev/programming-guides/json/>.
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use prost_types::ListValue;
+use prost_types::Struct;
+use prost_types::Value;
+use prost_types::value::Kind;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+use serde::de::Error;
+
+/// For optional messages with a string JSON form that matches their `Display` and `FromStr`
+/// implementations: `Timestamp` as RFC 3339, and `Duration` as seconds like `"1.5s"`.
+pub mod option_string {
+    use super::{Deserialize, Deserializer, Display, Error, FromStr, Serializer};
+
+    pub fn serialize<T: Display, S: Serializer>(
+        value: &Option<T>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => serializer.collect_str(value),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        Option::<String>::deserialize(deserializer)?
+            .map(|value| value.parse().map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+/// For `bytes` fields, which are base64 strings. Parsing also accepts the URL-safe alphabet and
+/// missing padding.
+pub mod base64 {
+    use base64::Engine;
+    use base64::engine::DecodePaddingMode;
+    use base64::engine::GeneralPurpose;
+    use base64::engine::GeneralPurposeConfig;
+    use base64::engine::general_purpose::STANDARD;
+
+    use super::{Deserialize, Deserializer, Error, Serializer};
+
+    const LENIENT: GeneralPurposeConfig =
+        GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent);
+
+    pub fn serialize<S: Serializer>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(value))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        let engine = if value.contains(['-', '_']) {
+            GeneralPurpose::new(&base64::alphabet::URL_SAFE, LENIENT)
+        } else {
+            GeneralPurpose::new(&base64::alphabet::STANDARD, LENIENT)
+        };
+        engine.decode(value).map_err(D::Error::custom)
+    }
+}
+
+/// For `int64` and `uint64` fields, which are strings so JavaScript can read them without losing
+/// precision. Parsing also accepts numbers.
+pub mod int_string {
+    use super::{Deserialize, Deserializer, Display, Error, FromStr, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrNumber<T> {
+        String(String),
+        Number(T),
+    }
+
+    pub fn serialize<T: Display, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: FromStr + Deserialize<'de>,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        match StringOrNumber::deserialize(deserializer)? {
+            StringOrNumber::String(value) => value.parse().map_err(D::Error::custom),
+            StringOrNumber::Number(value) => Ok(value),
+        }
+    }
+}
+
+/// For optional `Struct` messages, which are JSON objects.
+pub mod option_struct {
+    use super::{Deserialize, Deserializer, Serialize, Serializer, Struct, from_json, to_json};
+
+    pub fn serialize<S: Serializer>(
+        value: &Option<Struct>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        value.as_ref().map(to_json).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Struct>, D::Error> {
+        Ok(
+            Option::<serde_json::Map<String, serde_json::Value>>::deserialize(deserializer)?
+                .map(from_json),
+        )
+    }
+}
+
+/// Returns `value` as a JSON object.
+#[must_use]
+pub fn to_json(value: &Struct) -> serde_json::Map<String, serde_json::Value> {
+    value
+        .fields
+        .iter()
+        .map(|(key, value)| (key.clone(), value_to_json(value)))
+        .collect()
+}
+
+/// Returns the `Struct` with the fields of `object`.
+#[must_use]
+pub fn from_json(object: serde_json::Map<String, serde_json::Value>) -> Struct {
+    Struct {
+        fields: object
+            .into_iter()
+            .map(|(key, value)| (key, value_from_json(value)))
+            .collect(),
+    }
+}
+
+/// Returns `value` as JSON. Numbers that are not finite become `null`.
+#[must_use]
+pub fn value_to_json(value: &Value) -> serde_json::Value {
+    match &value.kind {
+        None | Some(Kind::NullValue(_)) => serde_json::Value::Null,
+        Some(Kind::NumberValue(number)) => serde_json::Value::from(*number),
+        Some(Kind::StringValue(string)) => serde_json::Value::String(string.clone()),
+        Some(Kind::BoolValue(bool)) => serde_json::Value::Bool(*bool),
+        Some(Kind::StructValue(value)) => serde_json::Value::Object(to_json(value)),
+        Some(Kind::ListValue(list)) => list.values.iter().map(value_to_json).collect(),
+    }
+}
+
+/// Returns the `Value` for `value`. Numbers are converted to `f64`.
+#[must_use]
+pub fn value_from_json(value: serde_json::Value) -> Value {
+    let kind = match value {
+        serde_json::Value::Null => Kind::NullValue(prost_types::NullValue::NullValue.into()),
+        serde_json::Value::Bool(bool) => Kind::BoolValue(bool),
+        serde_json::Value::Number(number) => Kind::NumberValue(number.as_f64().unwrap_or(f64::NAN)),
+        serde_json::Value::String(string) => Kind::StringValue(string),
+        serde_json::Value::Array(values) => Kind::ListValue(ListValue {
+            values: values.into_iter().map(value_from_json).collect(),
+        }),
+        serde_json::Value::Object(object) => Kind::StructValue(from_json(object)),
+    };
+    Value { kind: Some(kind) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::echopb::EchoRequest;
+    use crate::echopb::EchoResponse;
+
+    #[test]
+    fn test_well_known_types_json() {
+        let attributes = serde_json::json!({
+            "name": "echo",
+            "count": 2.0,
+            "enabled": true,
+            "tags": ["a", null],
+            "nested": {"key": "value"},
+        });
+        let serde_json::Value::Object(attributes) = attributes else {
+            unreachable!();
+        };
+        let request = EchoRequest {
+            input: "hello".to_string(),
+            delay: Some(prost_types::Duration {
+                seconds: 1,
+                nanos: 500_000_000,
+            }),
+            attributes: Some(from_json(attributes.clone())),
+            ..EchoRequest::default()
+        };
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["delay"], "1.500s");
+        assert_eq!(json["attributes"], serde_json::Value::Object(attributes));
+        assert_eq!(
+            serde_json::from_value::<EchoRequest>(json).unwrap(),
+            request
+        );
+
+        let response = EchoResponse {
+            server_receive_time: Some(prost_types::Timestamp {
+                seconds: 1_700_000_000,
+                nanos: 0,
+            }),
+            ..EchoResponse::default()
+        };
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["serverReceiveTime"], "2023-11-14T22:13:20Z");
+        assert_eq!(json["serverSendTime"], serde_json::Value::Null);
+        assert_eq!(
+            serde_json::from_value::<EchoResponse>(json).unwrap(),
+            response
+        );
+
+        assert!(serde_json::from_str::<EchoRequest>(r#"{"delay": "soon"}"#).is_err());
+    }
+
+    #[test]
+    fn test_bytes_and_int64_json() {
+        let request = EchoRequest {
+            payload: vec![0xfb, 0xff, 0x00],
+            send_time_unix_nanos: -1,
+            sequence: u64::MAX,
+            ..EchoRequest::default()
+        };
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["payload"], "+/8A");
+        assert_eq!(json["sendTimeUnixNanos"], "-1");
+        assert_eq!(json["sequence"], "18446744073709551615");
+        assert_eq!(
+            serde_json::from_value::<EchoRequest>(json).unwrap(),
+            request
+        );
+
+        // numbers, and URL-safe base64 without padding, are also accepted
+        let parsed: EchoResponse =
+            serde_json::from_str(r#"{"payload": "-_8", "sendTimeUnixNanos": 5, "sequence": 7}"#)
+                .unwrap();
+        assert_eq!(parsed.payload, [0xfb, 0xff]);
+        assert_eq!(parsed.send_time_unix_nanos, 5);
+        assert_eq!(parsed.sequence, 7);
+
+        assert!(serde_json::from_str::<EchoRequest>(r#"{"payload": "!"}"#).is_err());
+        assert!(serde_json::from_str::<EchoRequest>(r#"{"sequence": "-1"}"#).is_err());
+    }
+}
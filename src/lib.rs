@@ -1,45 +1,88 @@
+use std::fmt::Display;
 use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::task::Context;
+use std::task::Poll;
 
+use bytes::Buf;
+use bytes::BufMut;
+use bytes::Bytes;
 use chrono::SecondsFormat;
+use hyper_util::rt::TokioIo;
 use prost::Message;
+use tokio::net::UnixStream;
+use tokio::task::futures::TaskLocalFuture;
 use tonic::codec::BufferSettings;
 use tonic::codec::Codec;
+use tonic::codec::DecodeBuf;
+use tonic::codec::Decoder;
+use tonic::codec::EncodeBuf;
+use tonic::codec::Encoder;
+use tonic::codegen::Service;
+use tonic::server::NamedService;
+use tonic::transport::Certificate;
+use tonic::transport::Channel;
+use tonic::transport::ClientTlsConfig;
+use tonic::transport::Endpoint;
+use tonic::transport::Identity;
+use tonic::transport::Uri;
+use tonic::transport::server::TcpConnectInfo;
+use tonic::transport::server::TlsConnectInfo;
+use tonic::transport::server::UdsConnectInfo;
 use tonic_prost::ProstCodec;
+use x509_parser::prelude::FromDer;
+use x509_parser::prelude::GeneralName;
+use x509_parser::prelude::X509Certificate;
 
 pub mod echopb {
     #![expect(clippy::pedantic, clippy::nursery)]
     tonic::include_proto!("echopb");
 }
 
+pub mod any_registry;
+pub mod backpressure;
+pub mod chat_room;
+pub mod codec_registry;
+pub mod compression;
+pub mod echo_controls;
+pub mod echo_metadata;
+pub mod echo_service;
+pub mod fault;
+pub mod json_codec;
+pub mod json_wkt;
+pub mod rich_error;
+pub mod shutdown;
+
+/// The encoded `FileDescriptorSet` for `proto/echo.proto`, for the reflection service.
+pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("echo_descriptor");
+
 pub mod custom_codec_echopb {
     #![expect(clippy::pedantic, clippy::nursery)]
     tonic::include_proto!("custom_codec/echopb");
 }
 
-const PROTOBUF_TYPE_URL_PREFIX: &str = "type.googleapis.com/";
-
-// Must be manually implemented since it does not yet have prost-build support.
-impl prost::Name for echopb::Example1 {
-    const NAME: &'static str = "Example1";
-    const PACKAGE: &'static str = "echopb";
-
-    fn type_url() -> String {
-        // prost does not agree with Go's protobuf implementation:
-        // "The default type URL for a given message type is
-        // type.googleapis.com/_packagename_._messagename_."
-        // https://protobuf.dev/programming-guides/proto3/#any
-        format!("{PROTOBUF_TYPE_URL_PREFIX}{}", Self::full_name())
-    }
+/// The `json.echopb.Echo` service: `echopb.Echo` with JSON messages. See [`json_codec`].
+pub mod json_echopb {
+    #![expect(clippy::pedantic, clippy::nursery)]
+    include!(concat!(env!("OUT_DIR"), "/json.echopb.Echo.rs"));
 }
 
-// Must be manually implemented since it does not yet have prost-build support.
-impl prost::Name for echopb::Example2 {
-    const NAME: &'static str = "Example2";
-    const PACKAGE: &'static str = "echopb";
+/// `echopb.Echo` with JSON messages, for `application/grpc+json` requests. See
+/// [`codec_registry`].
+pub mod json_codec_echopb {
+    #![expect(clippy::pedantic, clippy::nursery)]
+    include!(concat!(env!("OUT_DIR"), "/json_codec/echopb.Echo.rs"));
+}
 
-    fn type_url() -> String {
-        format!("{PROTOBUF_TYPE_URL_PREFIX}{}", Self::full_name())
-    }
+/// `echopb.Echo` with undecoded messages. See [`RawBytesCodec`].
+pub mod raw_codec_echopb {
+    #![expect(
+        clippy::pedantic,
+        clippy::nursery,
+        clippy::default_constructed_unit_structs
+    )]
+    include!(concat!(env!("OUT_DIR"), "/raw_codec/echopb.Echo.rs"));
 }
 
 /// Returns the current `SystemTime` formatted for a log file.
@@ -49,8 +92,233 @@ pub fn now_formatted() -> String {
     now_utc.to_rfc3339_opts(SecondsFormat::Micros, true)
 }
 
-#[derive(Debug, Clone, Copy, Default)]
-pub struct CustomResponseCodec<T, U>(PhantomData<(T, U)>);
+/// Returns the current time in nanoseconds since the Unix epoch, for
+/// `EchoRequest.send_time_unix_nanos`.
+///
+/// # Panics
+///
+/// Panics after the year 2262, when the time no longer fits in an `i64`.
+#[must_use]
+pub fn unix_nanos_now() -> i64 {
+    chrono::Utc::now()
+        .timestamp_nanos_opt()
+        .expect("the current time fits in i64 nanoseconds until 2262")
+}
+
+/// Prefix for gRPC URLs that connect to a Unix domain socket, e.g. `unix:///tmp/echo.sock`.
+pub const UNIX_URL_PREFIX: &str = "unix://";
+
+/// Connects to the gRPC server at `grpc_url`, which is either an `http://` or `https://` URL, or
+/// a `unix:///path` URL for a Unix domain socket. Uses TLS if `tls_config` is set.
+pub async fn connect(
+    grpc_url: &str,
+    tls_config: Option<ClientTlsConfig>,
+) -> Result<Channel, tonic::transport::Error> {
+    let Some(socket_path) = grpc_url.strip_prefix(UNIX_URL_PREFIX) else {
+        let mut endpoint = Endpoint::from_shared(grpc_url.to_string())?;
+        if let Some(tls_config) = tls_config {
+            endpoint = endpoint.tls_config(tls_config)?;
+        }
+        return endpoint.connect().await;
+    };
+
+    // The connector ignores the URI: it is only used for the HTTP/2 :authority header, and the
+    // scheme decides if tonic uses TLS.
+    let endpoint = match tls_config {
+        Some(tls_config) => Endpoint::from_static("https://localhost").tls_config(tls_config)?,
+        None => Endpoint::from_static("http://localhost"),
+    };
+    let socket_path = socket_path.to_string();
+    endpoint
+        .connect_with_connector(tower::service_fn(move |_: Uri| {
+            let socket_path = socket_path.clone();
+            async move {
+                Ok::<_, std::io::Error>(TokioIo::new(UnixStream::connect(socket_path).await?))
+            }
+        }))
+        .await
+}
+
+/// Command-line arguments to configure TLS for the clients.
+#[derive(Debug, Clone, clap::Args)]
+pub struct ClientTlsArgs {
+    /// PEM file with the CA certificate used to verify the server. Enables TLS, which requires an
+    /// `https://` URL.
+    #[clap(long)]
+    pub ca_cert: Option<PathBuf>,
+
+    /// PEM file with the client certificate to send for mutual TLS.
+    #[clap(long, requires_all = ["ca_cert", "tls_key"])]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key for `--tls-cert`.
+    #[clap(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+}
+
+impl ClientTlsArgs {
+    /// Returns the TLS configuration for the arguments, or None if TLS is not enabled.
+    pub fn tls_config(&self) -> Result<Option<ClientTlsConfig>, std::io::Error> {
+        let Some(ca_cert_path) = &self.ca_cert else {
+            return Ok(None);
+        };
+        let ca_cert = Certificate::from_pem(std::fs::read(ca_cert_path)?);
+        let mut tls_config = ClientTlsConfig::new().ca_certificate(ca_cert);
+        if let (Some(cert_path), Some(key_path)) = (&self.tls_cert, &self.tls_key) {
+            let identity = Identity::from_pem(std::fs::read(cert_path)?, std::fs::read(key_path)?);
+            tls_config = tls_config.identity(identity);
+        }
+        Ok(Some(tls_config))
+    }
+}
+
+/// The identity in a TLS peer's certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdentity {
+    pub subject: String,
+    pub subject_alt_names: Vec<String>,
+}
+
+impl PeerIdentity {
+    /// Parses the identity from a DER-encoded X.509 certificate.
+    pub fn from_der(
+        cert_der: &[u8],
+    ) -> Result<Self, x509_parser::nom::Err<x509_parser::error::X509Error>> {
+        let (_, cert) = X509Certificate::from_der(cert_der)?;
+        let subject_alt_names = match cert.subject_alternative_name() {
+            Ok(Some(san)) => san
+                .value
+                .general_names
+                .iter()
+                .map(format_general_name)
+                .collect(),
+            Ok(None) => vec![],
+            Err(err) => return Err(x509_parser::nom::Err::Error(err)),
+        };
+        Ok(Self {
+            subject: cert.subject().to_string(),
+            subject_alt_names,
+        })
+    }
+
+    /// Returns the identity from the verified client certificate of a request, or None if the
+    /// connection does not use TLS or the client did not send a certificate.
+    #[must_use]
+    pub fn from_request<T>(request: &tonic::Request<T>) -> Option<Self> {
+        let extensions = request.extensions();
+        let peer_certs = extensions
+            .get::<TlsConnectInfo<TcpConnectInfo>>()
+            .and_then(TlsConnectInfo::peer_certs)
+            .or_else(|| {
+                extensions
+                    .get::<TlsConnectInfo<UdsConnectInfo>>()
+                    .and_then(TlsConnectInfo::peer_certs)
+            })?;
+        // the first certificate is the peer's; any others are the chain to the CA
+        let cert_der = peer_certs.first()?;
+        match Self::from_der(cert_der) {
+            Ok(peer_identity) => Some(peer_identity),
+            Err(err) => {
+                eprintln!("failed parsing verified peer certificate: {err}");
+                None
+            }
+        }
+    }
+}
+
+/// Formats a subject alternative name like OpenSSL, e.g. `DNS:localhost` or `IP:127.0.0.1`.
+fn format_general_name(name: &GeneralName<'_>) -> String {
+    match name {
+        GeneralName::DNSName(dns_name) => format!("DNS:{dns_name}"),
+        GeneralName::RFC822Name(email) => format!("email:{email}"),
+        GeneralName::URI(uri) => format!("URI:{uri}"),
+        GeneralName::IPAddress(ip_bytes) => {
+            let ip_addr = <[u8; 4]>::try_from(*ip_bytes)
+                .map(IpAddr::from)
+                .or_else(|_| <[u8; 16]>::try_from(*ip_bytes).map(IpAddr::from));
+            ip_addr.map_or_else(|_| name.to_string(), |ip_addr| format!("IP:{ip_addr}"))
+        }
+        _ => name.to_string(),
+    }
+}
+
+impl Display for PeerIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "subject={:?} subject_alt_names=[{}]",
+            self.subject,
+            self.subject_alt_names.join(", ")
+        )
+    }
+}
+
+/// The default buffer size for [`CustomResponseCodec`].
+pub const DEFAULT_CUSTOM_CODEC_BUFFER_SIZE: usize = 512;
+/// The default yield threshold for [`CustomResponseCodec`].
+pub const DEFAULT_CUSTOM_CODEC_YIELD_THRESHOLD: usize = 4096;
+
+/// The buffer settings of the encoders and decoders of a [`CustomResponseCodec`]. See
+/// [`BufferSettings`], which has no accessors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CustomCodecBufferSettings {
+    pub buffer_size: usize,
+    pub yield_threshold: usize,
+}
+
+impl Default for CustomCodecBufferSettings {
+    fn default() -> Self {
+        Self {
+            buffer_size: DEFAULT_CUSTOM_CODEC_BUFFER_SIZE,
+            yield_threshold: DEFAULT_CUSTOM_CODEC_YIELD_THRESHOLD,
+        }
+    }
+}
+
+impl From<CustomCodecBufferSettings> for BufferSettings {
+    fn from(settings: CustomCodecBufferSettings) -> Self {
+        Self::new(settings.buffer_size, settings.yield_threshold)
+    }
+}
+
+tokio::task_local! {
+    // The generated services create the codec for each call with CustomResponseCodec::default(),
+    // so WithCodecBufferSettings passes its settings to them while the call runs.
+    static CALL_BUFFER_SETTINGS: CustomCodecBufferSettings;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CustomResponseCodec<T, U> {
+    buffer_settings: CustomCodecBufferSettings,
+    messages: PhantomData<(T, U)>,
+}
+
+impl<T, U> CustomResponseCodec<T, U> {
+    #[must_use]
+    pub const fn new(buffer_settings: CustomCodecBufferSettings) -> Self {
+        Self {
+            buffer_settings,
+            messages: PhantomData,
+        }
+    }
+
+    #[must_use]
+    pub const fn buffer_settings(&self) -> CustomCodecBufferSettings {
+        self.buffer_settings
+    }
+}
+
+impl<T, U> Default for CustomResponseCodec<T, U> {
+    /// Uses the settings of the [`WithCodecBufferSettings`] serving the current call, or the
+    /// default settings outside of one.
+    fn default() -> Self {
+        Self::new(
+            CALL_BUFFER_SETTINGS
+                .try_with(|settings| *settings)
+                .unwrap_or_default(),
+        )
+    }
+}
 
 impl<T, U> Codec for CustomResponseCodec<T, U>
 where
@@ -67,11 +335,165 @@ where
         // Here, we will just customize the prost codec's internal buffer settings.
         // You can of course implement a complete Codec, Encoder, and Decoder if
         // you wish!
-        panic!("encoder");
-        // ProstCodec::<T, U>::raw_encoder(BufferSettings::new(512, 4096))
+        ProstCodec::<T, U>::raw_encoder(self.buffer_settings.into())
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        ProstCodec::<T, U>::raw_decoder(self.buffer_settings.into())
+    }
+}
+
+/// Wraps a server generated with [`CustomResponseCodec`], so the codecs of its calls use
+/// `buffer_settings`. Servers wrapped with different settings can run in the same process.
+#[derive(Debug, Clone)]
+pub struct WithCodecBufferSettings<S> {
+    inner: S,
+    buffer_settings: CustomCodecBufferSettings,
+}
+
+impl<S> WithCodecBufferSettings<S> {
+    #[must_use]
+    pub const fn new(inner: S, buffer_settings: CustomCodecBufferSettings) -> Self {
+        Self {
+            inner,
+            buffer_settings,
+        }
+    }
+}
+
+impl<S: NamedService> NamedService for WithCodecBufferSettings<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, Req> Service<Req> for WithCodecBufferSettings<S>
+where
+    S: Service<Req>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = TaskLocalFuture<CustomCodecBufferSettings, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Req) -> Self::Future {
+        // the generated server creates the codec when the future is first polled
+        CALL_BUFFER_SETTINGS.scope(self.buffer_settings, self.inner.call(request))
+    }
+}
+
+/// Passes the undecoded bytes of each message to the service, and sends its bytes unchanged.
+///
+/// Services can forward or record messages without decoding and encoding them again.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RawBytesCodec;
+
+impl Codec for RawBytesCodec {
+    type Encode = Bytes;
+    type Decode = Bytes;
+
+    type Encoder = Self;
+    type Decoder = Self;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        Self
     }
 
     fn decoder(&mut self) -> Self::Decoder {
-        ProstCodec::<T, U>::raw_decoder(BufferSettings::new(512, 4096))
+        Self
+    }
+}
+
+impl Encoder for RawBytesCodec {
+    type Item = Bytes;
+    type Error = tonic::Status;
+
+    fn encode(&mut self, item: Self::Item, buf: &mut EncodeBuf<'_>) -> Result<(), Self::Error> {
+        buf.put(item);
+        Ok(())
+    }
+}
+
+impl Decoder for RawBytesCodec {
+    type Item = Bytes;
+    type Error = tonic::Status;
+
+    fn decode(&mut self, buf: &mut DecodeBuf<'_>) -> Result<Option<Self::Item>, Self::Error> {
+        // an empty message is a valid protobuf message with all fields set to defaults
+        Ok(Some(buf.copy_to_bytes(buf.remaining())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_identity_from_der() {
+        let mut params =
+            rcgen::CertificateParams::new(vec!["localhost".to_string(), "127.0.0.1".to_string()])
+                .unwrap();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "test client");
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+
+        let peer_identity = PeerIdentity::from_der(cert.der()).unwrap();
+        assert_eq!(peer_identity.subject, "CN=test client");
+        assert_eq!(
+            peer_identity.subject_alt_names,
+            vec!["DNS:localhost", "IP:127.0.0.1"]
+        );
+
+        PeerIdentity::from_der(b"not a certificate").unwrap_err();
+    }
+
+    #[test]
+    fn test_generated_type_urls() {
+        use prost::Name;
+
+        assert_eq!(
+            echopb::Example1::type_url(),
+            "type.googleapis.com/echopb.Example1"
+        );
+        assert_eq!(
+            echopb::EchoRequest::type_url(),
+            "type.googleapis.com/echopb.EchoRequest"
+        );
+        assert_eq!(
+            custom_codec_echopb::Example2::type_url(),
+            "type.googleapis.com/echopb.Example2"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_custom_codec_buffer_settings() {
+        type EchoCodec = CustomResponseCodec<echopb::EchoResponse, echopb::EchoRequest>;
+
+        assert_eq!(
+            EchoCodec::default().buffer_settings(),
+            CustomCodecBufferSettings::default()
+        );
+
+        // each wrapped service passes its own settings to the codecs of its calls
+        let codec_settings = tower::service_fn(|()| async {
+            Ok::<_, std::convert::Infallible>(EchoCodec::default().buffer_settings())
+        });
+        let large = CustomCodecBufferSettings {
+            buffer_size: 1024,
+            yield_threshold: 8192,
+        };
+        let small = CustomCodecBufferSettings {
+            buffer_size: 64,
+            yield_threshold: 128,
+        };
+        let mut large_service = WithCodecBufferSettings::new(codec_settings, large);
+        let mut small_service = WithCodecBufferSettings::new(codec_settings, small);
+        let (large_result, small_result) =
+            tokio::join!(large_service.call(()), small_service.call(()));
+        assert_eq!(large_result.unwrap(), large);
+        assert_eq!(small_result.unwrap(), small);
     }
 }
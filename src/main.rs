@@ -1,260 +1,1397 @@
 use std::net::SocketAddr;
-use std::pin::Pin;
+use std::num::NonZeroUsize;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use bytes::Bytes;
 use clap::Parser;
-use prost::Message;
-use prost_types::Any;
-use rustgrpcdemo::echopb::EchoRequest;
-use rustgrpcdemo::echopb::EchoResponse;
-use rustgrpcdemo::echopb::echo_server::Echo;
+use rustgrpcdemo::CustomCodecBufferSettings;
+use rustgrpcdemo::DEFAULT_CUSTOM_CODEC_BUFFER_SIZE;
+use rustgrpcdemo::DEFAULT_CUSTOM_CODEC_YIELD_THRESHOLD;
+use rustgrpcdemo::UNIX_URL_PREFIX;
+use rustgrpcdemo::WithCodecBufferSettings;
+use rustgrpcdemo::backpressure::DEFAULT_STREAM_CHANNEL_CAPACITY;
+use rustgrpcdemo::chat_room::DEFAULT_ROOM_BUFFER;
+use rustgrpcdemo::codec_registry::CodecRegistry;
+use rustgrpcdemo::compression::ACCEPTED_ENCODINGS;
+use rustgrpcdemo::compression::Compression;
+use rustgrpcdemo::compression::CountBytes;
+use rustgrpcdemo::custom_codec_echopb::echo_server::EchoServer as CustomCodecEchoServer;
+use rustgrpcdemo::echo_metadata::ResponseTrailers;
+use rustgrpcdemo::echo_service::EchoService;
+use rustgrpcdemo::echo_service::ErrorDetailType;
+use rustgrpcdemo::echopb::echo_server;
 use rustgrpcdemo::echopb::echo_server::EchoServer;
-use rustgrpcdemo::echopb::{Example1, Example2};
+use rustgrpcdemo::fault::FaultArgs;
+use rustgrpcdemo::fault::FaultInjector;
+use rustgrpcdemo::json_codec::JsonContentType;
+use rustgrpcdemo::json_codec_echopb::echo_server::EchoServer as JsonCodecEchoServer;
+use rustgrpcdemo::json_echopb::echo_server::EchoServer as JsonEchoServer;
 use rustgrpcdemo::now_formatted;
-use tokio_stream::wrappers::ReceiverStream;
-use tonic::Request;
-use tonic::Response;
-use tonic::Status;
+use rustgrpcdemo::shutdown::Shutdown;
+use tokio::net::UnixListener;
+use tokio::signal::unix::SignalKind;
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::server::NamedService;
+use tonic::transport::Certificate;
+use tonic::transport::Identity;
 use tonic::transport::Server;
+use tonic::transport::ServerTlsConfig;
+use tonic::transport::server::Router;
+use tonic::transport::server::TcpIncoming;
+use tonic_health::ServingStatus;
+use tonic_health::pb::health_server::Health;
+use tonic_health::pb::health_server::HealthServer;
+use tonic_health::server::HealthReporter;
 
+/// An address the server listens on: a TCP socket address, or a Unix domain socket path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(socket_path) = s.strip_prefix(UNIX_URL_PREFIX) {
+            if socket_path.is_empty() {
+                return Err(format!("invalid listen address {s:?}: missing socket path"));
+            }
+            return Ok(Self::Unix(PathBuf::from(socket_path)));
+        }
+        s.parse()
+            .map(Self::Tcp)
+            .map_err(|err| format!("invalid listen address {s:?}: {err}"))
+    }
+}
+
+/// Removes a Unix domain socket file when dropped, so it does not go stale.
 #[derive(Debug)]
-struct EchoService {
-    err_details: bool,
+struct UnixSocketFile {
+    path: PathBuf,
 }
 
-impl EchoService {
-    const fn new(err_details: bool) -> Self {
-        Self { err_details }
+impl Drop for UnixSocketFile {
+    fn drop(&mut self) {
+        println!("removing unix socket {} ...", self.path.display());
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            eprintln!("failed removing unix socket {}: {err}", self.path.display());
+        }
     }
 }
 
-impl EchoService {}
-
-#[tonic::async_trait]
-impl Echo for EchoService {
-    async fn echo(&self, request: Request<EchoRequest>) -> Result<Response<EchoResponse>, Status> {
-        println!("echo request.msg={:?}", request.get_ref());
-        if self.err_details {
-            let details1_any = Any::from_msg(&Example1 { int64_value: 99 }).unwrap();
-            let example2 = Example2 {
-                float64_value: 1.234,
-            };
-            let details2_any = Any::from_msg(&example2).unwrap();
-
-            let status_pb = tonic_types::Status {
-                code: tonic::Code::Internal as i32,
-                message: "error with 2 details".to_string(),
-                details: vec![details1_any, details2_any],
-            };
-            // encode the status and attach it
-            let status_bytes = status_pb.encode_to_vec();
-            let status = tonic::Status::with_details(
-                tonic::Code::Internal,
-                "error with 2 details".to_string(),
-                Bytes::from(status_bytes),
-            );
+/// Removes the Unix domain socket file at `path` if no server is listening on it. Returns an
+/// error if another server is listening, or if the path exists but is not a socket.
+fn remove_stale_socket(path: &Path) -> Result<(), std::io::Error> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
 
-            return Err(status);
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AddrInUse,
+            format!("another server is listening on {}", path.display()),
+        )),
+        Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {
+            println!("removing stale unix socket {} ...", path.display());
+            std::fs::remove_file(path)
         }
+        Err(err) => Err(err),
+    }
+}
 
-        let response = EchoResponse {
-            output: format!("echoed: {}", request.get_ref().input),
-        };
-        Ok(Response::new(response))
-    }
-
-    type EchoBiDirStream = Pin<
-        Box<dyn tokio_stream::Stream<Item = Result<EchoResponse, tonic::Status>> + Send + 'static>,
-    >;
-
-    async fn echo_bi_dir(
-        &self,
-        request: tonic::Request<tonic::Streaming<EchoRequest>>,
-    ) -> std::result::Result<tonic::Response<Self::EchoBiDirStream>, tonic::Status> {
-        println!("echo_bi_dir: starting new echo_bi_dir stream ...");
-        let request_stream = request.into_inner();
-        let (response_stream_sender, response_stream_rx) = tokio::sync::mpsc::channel(1);
-
-        tokio::spawn(async move {
-            let stream_result = do_echo_bi_dir(request_stream, &response_stream_sender).await;
-            if let Err(stream_err) = stream_result {
-                eprintln!("do_echo_bi_dir returned error; sending to caller: {stream_err}");
-                let final_send_result = response_stream_sender
-                    .send(Err(tonic::Status::internal(format!(
-                        "do_echo_bi_dir returned error: {stream_err}"
-                    ))))
-                    .await;
-                if let Err(send_err) = final_send_result {
-                    eprintln!("echo_bi_dir failed sending error to caller; send error: {send_err}");
-                }
-            }
-        });
+/// A bound listener that is ready to serve.
+#[derive(Debug)]
+enum Listener {
+    Tcp(TcpIncoming),
+    Unix {
+        incoming: UnixListenerStream,
+        socket_file: UnixSocketFile,
+    },
+}
 
-        Ok(Response::new(Box::pin(ReceiverStream::new(
-            response_stream_rx,
-        ))))
+impl Listener {
+    /// Binds `listen_addr`, printing the actual address of the listener.
+    fn bind(listen_addr: &ListenAddr) -> Result<Self, std::io::Error> {
+        match listen_addr {
+            ListenAddr::Tcp(socket_addr) => {
+                let incoming = TcpIncoming::bind(*socket_addr)?;
+                println!("listening on {} ...", incoming.local_addr()?);
+                Ok(Self::Tcp(incoming))
+            }
+            ListenAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+                let listener = UnixListener::bind(path)?;
+                let socket_file = UnixSocketFile { path: path.clone() };
+                println!("listening on {UNIX_URL_PREFIX}{} ...", path.display());
+                Ok(Self::Unix {
+                    incoming: UnixListenerStream::new(listener),
+                    socket_file,
+                })
+            }
+        }
     }
 }
 
-async fn do_echo_bi_dir(
-    request_stream: tonic::Streaming<EchoRequest>,
-    response_stream_sender: &tokio::sync::mpsc::Sender<Result<EchoResponse, tonic::Status>>,
-) -> Result<(), tonic::Status> {
-    let mut request_stream = request_stream;
-    while let Some(request) = request_stream.message().await? {
-        println!(
-            "{} echo_bi_dir received request.input={:?}",
-            now_formatted(),
-            request.input
-        );
+#[derive(Debug, Parser)]
+#[expect(
+    clippy::struct_excessive_bools,
+    reason = "independent command line flags"
+)]
+struct Args {
+    /// Returns a gRPC error with details that are compatible with other gRPC implementations.
+    #[clap(long, default_value_t = false)]
+    err_details: bool,
+
+    /// The details to attach to errors with details, as a comma-separated list. The standard
+    /// types are the `google.rpc` error details.
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = ErrorDetailType::DEFAULT
+    )]
+    err_detail_types: Vec<ErrorDetailType>,
+
+    /// The number of messages buffered for each member of an `EchoBiDir` chat room, selected
+    /// with the `x-echo-room` header. Members that fall further behind skip messages.
+    #[clap(long, default_value_t = DEFAULT_ROOM_BUFFER)]
+    room_buffer: NonZeroUsize,
+
+    /// The number of responses buffered for each `EchoBiDir` and `EchoRepeat` stream. When it is
+    /// full, sends wait for the client to read: each stream prints how long when it ends.
+    #[clap(long, default_value_t = DEFAULT_STREAM_CHANNEL_CAPACITY)]
+    stream_channel_capacity: NonZeroUsize,
+
+    /// The HTTP/2 flow control window of each stream in bytes, up to 2^31-1. Uses hyper's
+    /// default if not set.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..=i64::from(i32::MAX)))]
+    http2_stream_window_size: Option<u32>,
+
+    /// The HTTP/2 flow control window of each connection in bytes, up to 2^31-1. Uses hyper's
+    /// default if not set.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..=i64::from(i32::MAX)))]
+    http2_connection_window_size: Option<u32>,
 
-        // tokio::time::sleep(Duration::from_millis(500)).await;
-        // println!("{} unblocked after sleeping", now_formatted(),);
+    /// The maximum number of concurrent streams, which are RPCs, on each HTTP/2 connection. At
+    /// least 1, since 0 would refuse every RPC.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_concurrent_streams: Option<u32>,
 
-        let response = EchoResponse {
-            output: format!("echoed: {}", request.input),
+    /// Use `CustomResponseCodec` instead of the normal prost codec for `application/grpc` and
+    /// `application/grpc+proto` requests. It is always used for `application/grpc+custom`.
+    #[clap(long, default_value_t = false)]
+    custom_codec: bool,
+
+    /// Initial buffer size in bytes for `CustomResponseCodec` when encoding and decoding.
+    #[clap(long, default_value_t = DEFAULT_CUSTOM_CODEC_BUFFER_SIZE)]
+    custom_codec_buffer_size: usize,
+
+    /// Bytes that `CustomResponseCodec` encodes on a stream before yielding them to the transport.
+    #[clap(long, default_value_t = DEFAULT_CUSTOM_CODEC_YIELD_THRESHOLD)]
+    custom_codec_yield_threshold: usize,
+
+    /// Use `RawBytesCodec` for `application/grpc` and `application/grpc+proto` requests, which
+    /// passes the undecoded request bytes to the service. Requests that are not valid
+    /// `EchoRequest` messages fail with `INVALID_ARGUMENT`.
+    #[clap(long, default_value_t = false, conflicts_with = "custom_codec")]
+    raw_codec: bool,
+
+    /// Address to listen on. May be repeated to listen on multiple addresses. Use port 0 to
+    /// listen on an unused port, which is printed on startup. Use `unix:///path` to listen on a
+    /// Unix domain socket.
+    #[clap(long = "listen", default_value = "[::1]:8001")]
+    listen_addrs: Vec<ListenAddr>,
+
+    /// PEM file with the server certificate. Enables TLS on all listeners.
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key for `--tls-cert`.
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM file with the CA certificate used to verify client certificates. Enables mutual TLS.
+    #[clap(long, requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// Accept clients without a certificate when using `--tls-client-ca`.
+    #[clap(long, default_value_t = false, requires = "tls_client_ca")]
+    tls_client_auth_optional: bool,
+
+    /// Milliseconds to report `NOT_SERVING` to health checks after SIGINT or SIGTERM before the
+    /// listeners stop, so load balancers see it and stop sending new RPCs.
+    #[clap(long, default_value_t = 1000)]
+    shutdown_health_delay_ms: u64,
+
+    /// Seconds to let in-flight RPCs finish after SIGINT or SIGTERM before aborting them.
+    #[clap(long, default_value_t = 10)]
+    shutdown_grace_secs: u64,
+
+    /// Compress responses if the client accepts compression. Tonic uses the first encoding the
+    /// client accepts, which may not be this one. The server accepts requests with any supported
+    /// compression.
+    #[clap(long, value_enum, default_value_t = Compression::None)]
+    compression: Compression,
+
+    #[command(flatten)]
+    faults: FaultArgs,
+}
+
+impl Args {
+    /// Returns the TLS configuration for the arguments, or None if TLS is not enabled.
+    fn server_tls_config(&self) -> Result<Option<ServerTlsConfig>, std::io::Error> {
+        let (Some(cert_path), Some(key_path)) = (&self.tls_cert, &self.tls_key) else {
+            return Ok(None);
         };
-        response_stream_sender
-            .send(Ok(response))
-            .await
-            .map_err(|err| {
-                tonic::Status::internal(format!(
-                    "do_echo_bi_dir: response_stream_sender.send() failed: {err}"
-                ))
-            })?;
-    }
-    let extra_message = EchoResponse {
-        output: "extra message after sender closed abcdef".to_string(),
-    };
-    println!(
-        "{} echo_bi_dir request stream ended; sending extra bonus message: {}",
-        now_formatted(),
-        extra_message.output
-    );
-    response_stream_sender
-        .send(Ok(extra_message))
-        .await
-        .map_err(|err| {
-            tonic::Status::internal(format!(
-                "do_echo_bi_dir: response_stream_sender.send() failed: {err}"
-            ))
-        })?;
-    Ok(())
+        let identity = Identity::from_pem(std::fs::read(cert_path)?, std::fs::read(key_path)?);
+        let mut tls_config = ServerTlsConfig::new().identity(identity);
+        if let Some(client_ca_path) = &self.tls_client_ca {
+            tls_config = tls_config
+                .client_ca_root(Certificate::from_pem(std::fs::read(client_ca_path)?))
+                .client_auth_optional(self.tls_client_auth_optional);
+        }
+        Ok(Some(tls_config))
+    }
+
+    /// Returns a server builder with the TLS and HTTP/2 settings from the arguments.
+    fn server_builder(&self) -> Result<Server, Box<dyn std::error::Error>> {
+        let mut server = Server::builder()
+            .initial_stream_window_size(self.http2_stream_window_size)
+            .initial_connection_window_size(self.http2_connection_window_size)
+            .max_concurrent_streams(self.max_concurrent_streams);
+        if let Some(tls_config) = self.server_tls_config()? {
+            println!(
+                "using TLS tls_cert={:?} tls_client_ca={:?} ...",
+                self.tls_cert, self.tls_client_ca
+            );
+            server = server.tls_config(tls_config)?;
+        }
+        Ok(server)
+    }
+
+    const fn custom_codec_buffer_settings(&self) -> CustomCodecBufferSettings {
+        CustomCodecBufferSettings {
+            buffer_size: self.custom_codec_buffer_size,
+            yield_threshold: self.custom_codec_yield_threshold,
+        }
+    }
+
+    /// Returns the Echo service, injecting the faults from the `--fault-*` flags.
+    fn echo_service(&self, shutdown: &Shutdown) -> Result<EchoService, Box<dyn std::error::Error>> {
+        let echo_service = EchoService::new(self.err_details, shutdown.clone())
+            .with_error_detail_types(self.err_detail_types.clone())
+            .with_room_buffer(self.room_buffer)
+            .with_stream_channel_capacity(self.stream_channel_capacity);
+        let Some(fault_policy) = self.faults.fault_policy()? else {
+            return Ok(echo_service);
+        };
+        println!("injecting faults {fault_policy:?} ...");
+        Ok(echo_service.with_faults(FaultInjector::new(fault_policy)))
+    }
 }
 
-#[derive(Debug)]
-struct EchoServiceCustomCodec {
-    err_details: bool,
+/// Binds all `listen_addrs`, printing the actual address of each listener.
+fn bind_all(listen_addrs: &[ListenAddr]) -> Result<Vec<Listener>, std::io::Error> {
+    let mut listeners = Vec::with_capacity(listen_addrs.len());
+    for listen_addr in listen_addrs {
+        let listener = Listener::bind(listen_addr).map_err(|err| {
+            std::io::Error::new(
+                err.kind(),
+                format!("failed to listen on {listen_addr:?}: {err}"),
+            )
+        })?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
 }
 
-impl EchoServiceCustomCodec {
-    const fn new(err_details: bool) -> Self {
-        Self { err_details }
+/// Serves `router` on all `listeners` concurrently until `shutdown` starts draining. Returns when
+/// any listener fails, or when all of them have stopped.
+async fn serve_all(
+    router: Router,
+    listeners: Vec<Listener>,
+    shutdown: &Shutdown,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut servers = tokio::task::JoinSet::new();
+    for listener in listeners {
+        let router = router.clone();
+        let shutdown = shutdown.clone();
+        let signal = async move { shutdown.draining().await };
+        match listener {
+            Listener::Tcp(incoming) => {
+                servers.spawn(router.serve_with_incoming_shutdown(incoming, signal))
+            }
+            Listener::Unix {
+                incoming,
+                socket_file,
+            } => servers.spawn(async move {
+                let serve_result = router.serve_with_incoming_shutdown(incoming, signal).await;
+                drop(socket_file);
+                serve_result
+            }),
+        };
+    }
+    while let Some(serve_result) = servers.join_next().await {
+        serve_result??;
     }
+    Ok(())
 }
 
-impl EchoServiceCustomCodec {}
-
-#[tonic::async_trait]
-impl rustgrpcdemo::custom_codec_echopb::echo_server::Echo for EchoServiceCustomCodec {
-    async fn echo(
-        &self,
-        request: Request<rustgrpcdemo::custom_codec_echopb::EchoRequest>,
-    ) -> Result<Response<rustgrpcdemo::custom_codec_echopb::EchoResponse>, Status> {
-        println!("echo request.msg={:?}", request.get_ref());
-        if self.err_details {
-            let details1_any = Any::from_msg(&Example1 { int64_value: 99 }).unwrap();
-            let example2 = Example2 {
-                float64_value: 1.234,
-            };
-            let details2_any = Any::from_msg(&example2).unwrap();
-
-            let status_pb = tonic_types::Status {
-                code: tonic::Code::Internal as i32,
-                message: "error with 2 details".to_string(),
-                details: vec![details1_any, details2_any],
-            };
-            // encode the status and attach it
-            let status_bytes = status_pb.encode_to_vec();
-            let status = tonic::Status::with_details(
-                tonic::Code::Internal,
-                "error with 2 details".to_string(),
-                Bytes::from(status_bytes),
-            );
+/// The health service names the server reports. The empty name is the health of the whole server.
+const HEALTH_SERVICE_NAMES: [&str; 3] = [
+    "",
+    echo_server::SERVICE_NAME,
+    rustgrpcdemo::json_echopb::echo_server::SERVICE_NAME,
+];
 
-            return Err(status);
+/// Configures a generated server to accept every supported encoding, and to compress responses
+/// with `compression` when the client accepts it.
+macro_rules! with_compression {
+    ($server:expr, $compression:expr) => {{
+        let mut server = $server;
+        for encoding in ACCEPTED_ENCODINGS {
+            server = server.accept_compressed(encoding);
+        }
+        if let Some(encoding) = $compression.encoding() {
+            server = server.send_compressed(encoding);
         }
+        server
+    }};
+}
 
-        let response = rustgrpcdemo::custom_codec_echopb::EchoResponse {
-            output: format!("echoed: {}", request.get_ref().input),
-        };
-        Ok(Response::new(response))
-    }
-
-    type EchoBiDirStream = Pin<
-        Box<
-            dyn tokio_stream::Stream<
-                    Item = Result<rustgrpcdemo::custom_codec_echopb::EchoResponse, tonic::Status>,
-                > + Send
-                + 'static,
-        >,
-    >;
-
-    async fn echo_bi_dir(
-        &self,
-        _request: tonic::Request<tonic::Streaming<rustgrpcdemo::custom_codec_echopb::EchoRequest>>,
-    ) -> std::result::Result<tonic::Response<Self::EchoBiDirStream>, tonic::Status> {
-        println!("echo_bi_dir: unimplemented for custom codec");
-        // TODO: implement?
-        Err(tonic::Status::unimplemented(
-            "echo_bi_dir unimplemented for custom codec",
+/// Returns a registry that serves `proto_service` for protobuf requests to `echopb.Echo`, and
+/// `echo_service` with the other codecs for `application/grpc+json` and `application/grpc+custom`.
+/// The custom codec uses `buffer_settings`. Prints the bytes of each RPC, and sends the echoed
+/// metadata in the trailers.
+fn register_echo_codecs<S: NamedService>(
+    proto_service: S,
+    echo_service: &Arc<EchoService>,
+    buffer_settings: CustomCodecBufferSettings,
+    compression: Compression,
+) -> CountBytes<ResponseTrailers<CodecRegistry<S>>> {
+    CountBytes::new(ResponseTrailers::new(
+        CodecRegistry::new(proto_service)
+            .register(
+                "json",
+                with_compression!(
+                    JsonCodecEchoServer::from_arc(echo_service.clone()),
+                    compression
+                ),
+            )
+            .register(
+                "custom",
+                WithCodecBufferSettings::new(
+                    with_compression!(
+                        CustomCodecEchoServer::from_arc(echo_service.clone()),
+                        compression
+                    ),
+                    buffer_settings,
+                ),
+            ),
+    ))
+}
+
+/// Adds `json.echopb.Echo`, and `echopb.Echo` with the codecs chosen by `args`, to `router`.
+fn add_echo_services(router: Router, args: &Args, echo_service: &Arc<EchoService>) -> Router {
+    let router = router.add_service(CountBytes::new(ResponseTrailers::new(
+        JsonContentType::new(with_compression!(
+            JsonEchoServer::from_arc(echo_service.clone()),
+            args.compression
+        )),
+    )));
+    let buffer_settings = args.custom_codec_buffer_settings();
+    if args.custom_codec {
+        println!("using custom codec {buffer_settings:?} for protobuf requests ...");
+        router.add_service(register_echo_codecs(
+            WithCodecBufferSettings::new(
+                with_compression!(
+                    CustomCodecEchoServer::from_arc(echo_service.clone()),
+                    args.compression
+                ),
+                buffer_settings,
+            ),
+            echo_service,
+            buffer_settings,
+            args.compression,
+        ))
+    } else if args.raw_codec {
+        println!("using raw bytes codec for protobuf requests ...");
+        router.add_service(register_echo_codecs(
+            with_compression!(
+                rustgrpcdemo::raw_codec_echopb::echo_server::EchoServer::from_arc(
+                    echo_service.clone()
+                ),
+                args.compression
+            ),
+            echo_service,
+            buffer_settings,
+            args.compression,
+        ))
+    } else {
+        router.add_service(register_echo_codecs(
+            with_compression!(EchoServer::from_arc(echo_service.clone()), args.compression),
+            echo_service,
+            buffer_settings,
+            args.compression,
         ))
     }
 }
 
-#[derive(Debug, Parser)]
-struct Args {
-    /// Returns a gRPC error with details that are compatible with other gRPC implementations.
-    #[clap(long, default_value_t = false)]
-    err_details: bool,
+/// Returns the health service to add to the server, which reports all services as `SERVING`, and
+/// the reporter to pass to [`report_not_serving`] when the server shuts down.
+async fn start_health_service() -> (HealthReporter, HealthServer<impl Health + use<>>) {
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    for service_name in HEALTH_SERVICE_NAMES {
+        health_reporter
+            .set_service_status(service_name, ServingStatus::Serving)
+            .await;
+    }
+    (health_reporter, health_service)
+}
 
-    /// Use `CustomResponseCodec` instead of the normal prost codec.
-    #[clap(long, default_value_t = false)]
-    custom_codec: bool,
+/// Switches all services to `NOT_SERVING`. The server should keep accepting connections for a
+/// while, so new health checks see it before the listeners stop.
+async fn report_not_serving(health_reporter: &HealthReporter) {
+    println!("{} health: reporting NOT_SERVING ...", now_formatted());
+    for service_name in HEALTH_SERVICE_NAMES {
+        health_reporter
+            .set_service_status(service_name, ServingStatus::NotServing)
+            .await;
+    }
+}
+
+/// Returns a reflection service builder that describes all services on the server.
+fn reflection_builder() -> tonic_reflection::server::Builder<'static> {
+    tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(rustgrpcdemo::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+}
+
+/// Waits for SIGINT (Ctrl-C) or SIGTERM. Returns the name of the signal.
+async fn wait_for_shutdown_signal() -> Result<&'static str, std::io::Error> {
+    let mut sigterm = tokio::signal::unix::signal(SignalKind::terminate())?;
+    tokio::select! {
+        ctrl_c_result = tokio::signal::ctrl_c() => ctrl_c_result.map(|()| "SIGINT"),
+        _ = sigterm.recv() => Ok("SIGTERM"),
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    const LISTEN_ADDR: &str = "::1";
-    const LISTEN_PORT: u16 = 8001;
-    let listen_addr_parsed = LISTEN_ADDR.parse().unwrap();
-    let listen_addr = SocketAddr::new(listen_addr_parsed, LISTEN_PORT);
+    // Time for aborted RPCs to send their final status and for connections to close.
+    const STOP_TIMEOUT: Duration = Duration::from_secs(5);
 
     let args = Args::parse();
 
     println!(
-        "listening on {LISTEN_ADDR}:{LISTEN_PORT} err_details={}...",
-        args.err_details
+        "starting server err_details={} err_detail_types={:?} ...",
+        args.err_details, args.err_detail_types
     );
+    let listeners = bind_all(&args.listen_addrs)?;
+
+    let mut server = args.server_builder()?;
 
     // construct the server and listen
-    // TODO: refactor the common code out? The traits make this tricky
-    if args.custom_codec {
-        println!("using custom codec ...");
-        let echo_service = EchoServiceCustomCodec::new(args.err_details);
-        Server::builder()
-            .add_service(
-                rustgrpcdemo::custom_codec_echopb::echo_server::EchoServer::new(echo_service),
-            )
-            .serve(listen_addr)
-            .await?;
-    } else {
-        let echo_service = EchoService::new(args.err_details);
-        Server::builder()
-            .add_service(EchoServer::new(echo_service))
-            .serve(listen_addr)
-            .await?;
+    let shutdown = Shutdown::new();
+    let (health_reporter, health_service) = start_health_service().await;
+    let server = server
+        .add_service(health_service)
+        .add_service(reflection_builder().build_v1()?)
+        .add_service(reflection_builder().build_v1alpha()?);
+    // the same service handles every codec, and JSON requests on json.echopb.Echo
+    println!("using compression={:?} for responses ...", args.compression);
+    let echo_service = Arc::new(args.echo_service(&shutdown)?);
+    let router = add_echo_services(server, &args, &echo_service);
+
+    let server = serve_all(router, listeners, &shutdown);
+    tokio::pin!(server);
+    let signal_name = tokio::select! {
+        serve_result = &mut server => return serve_result,
+        signal_result = wait_for_shutdown_signal() => signal_result?,
+    };
+
+    // keep serving while health checks report NOT_SERVING, so load balancers stop sending RPCs
+    let health_delay = Duration::from_millis(args.shutdown_health_delay_ms);
+    println!(
+        "{} received {signal_name}; reporting NOT_SERVING for {health_delay:?} before draining ...",
+        now_formatted()
+    );
+    report_not_serving(&health_reporter).await;
+    tokio::select! {
+        serve_result = &mut server => return serve_result,
+        () = tokio::time::sleep(health_delay) => {}
     }
 
-    Ok(())
+    let grace_period = Duration::from_secs(args.shutdown_grace_secs);
+    println!(
+        "{} draining {} in-flight RPCs for up to {grace_period:?} ...",
+        now_formatted(),
+        shutdown.summary().active
+    );
+    shutdown.start_draining();
+    if tokio::time::timeout(grace_period, shutdown.idle())
+        .await
+        .is_err()
+    {
+        println!(
+            "{} shutdown grace period expired; aborting {} in-flight RPCs ...",
+            now_formatted(),
+            shutdown.summary().active
+        );
+        shutdown.start_aborting();
+    }
+    let stop_result = tokio::time::timeout(STOP_TIMEOUT, &mut server).await;
+
+    let summary = shutdown.summary();
+    println!(
+        "{} shutdown complete: drained={} aborted={} still_active={}",
+        now_formatted(),
+        summary.drained,
+        summary.aborted,
+        summary.active
+    );
+    stop_result.unwrap_or_else(|_| {
+        eprintln!("server did not stop after {STOP_TIMEOUT:?}; exiting anyway");
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+    use rustgrpcdemo::echopb::EchoRequest;
+    use rustgrpcdemo::echopb::echo_client::EchoClient;
+    use tokio_stream::wrappers::ReceiverStream;
+    use tonic::Status;
+    use tonic::codegen::http;
+    use tonic::transport::ClientTlsConfig;
+
+    /// Serves `router` on a new localhost port until `shutdown` starts draining. Returns the
+    /// `http://` URL to connect to, and the task serving it.
+    fn start_test_server(
+        router: Router,
+        shutdown: &Shutdown,
+    ) -> (String, tokio::task::JoinHandle<Result<(), String>>) {
+        let listeners = bind_all(&["127.0.0.1:0".parse().unwrap()]).unwrap();
+        let Listener::Tcp(incoming) = &listeners[0] else {
+            panic!("expected tcp listener");
+        };
+        let grpc_url = format!("http://{}/", incoming.local_addr().unwrap());
+        let shutdown = shutdown.clone();
+        let server = tokio::spawn(async move {
+            serve_all(router, listeners, &shutdown)
+                .await
+                .map_err(|err| err.to_string())
+        });
+        (grpc_url, server)
+    }
+
+    #[tokio::test]
+    async fn test_serve_all_multiple_listeners() {
+        let listen_addrs: Vec<ListenAddr> = vec![
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.1:0".parse().unwrap(),
+        ];
+        let listeners = bind_all(&listen_addrs).unwrap();
+        let bound_addrs: Vec<SocketAddr> = listeners
+            .iter()
+            .map(|listener| match listener {
+                Listener::Tcp(incoming) => incoming.local_addr().unwrap(),
+                Listener::Unix { .. } => panic!("unexpected unix listener"),
+            })
+            .collect();
+        assert_ne!(bound_addrs[0].port(), 0);
+        assert_ne!(bound_addrs[0], bound_addrs[1]);
+
+        let shutdown = Shutdown::new();
+        let router = Server::builder()
+            .add_service(EchoServer::new(EchoService::new(false, shutdown.clone())));
+        tokio::spawn(async move { serve_all(router, listeners, &shutdown).await.unwrap() });
+
+        for bound_addr in bound_addrs {
+            let mut client = EchoClient::connect(format!("http://{bound_addr}/"))
+                .await
+                .unwrap();
+            let response = client
+                .echo(EchoRequest {
+                    input: "hello".to_string(),
+                    ..EchoRequest::default()
+                })
+                .await
+                .unwrap();
+            assert_eq!(response.get_ref().output, "echoed: hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unix_listener_removes_stale_socket() {
+        let socket_path =
+            std::env::temp_dir().join(format!("rustgrpcdemo_test_{}.sock", std::process::id()));
+        // leave a stale socket file behind, like a server that crashed
+        drop(std::os::unix::net::UnixListener::bind(&socket_path).unwrap());
+        assert!(socket_path.exists());
+
+        let listen_addr: ListenAddr = format!("{UNIX_URL_PREFIX}{}", socket_path.display())
+            .parse()
+            .unwrap();
+        assert_eq!(listen_addr, ListenAddr::Unix(socket_path.clone()));
+        let mut listeners = bind_all(&[listen_addr]).unwrap();
+
+        // a second server must not remove the socket of a running server
+        let err = Listener::bind(&ListenAddr::Unix(socket_path.clone())).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+
+        let Some(Listener::Unix {
+            incoming,
+            socket_file,
+        }) = listeners.pop()
+        else {
+            panic!("expected unix listener");
+        };
+        let router = Server::builder()
+            .add_service(EchoServer::new(EchoService::new(false, Shutdown::new())));
+        let server = tokio::spawn(router.serve_with_incoming(incoming));
+
+        let channel =
+            rustgrpcdemo::connect(&format!("{UNIX_URL_PREFIX}{}", socket_path.display()), None)
+                .await
+                .unwrap();
+        let response = EchoClient::new(channel)
+            .echo(EchoRequest {
+                input: "hello".to_string(),
+                ..EchoRequest::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.get_ref().output, "echoed: hello");
+
+        server.abort();
+        drop(socket_file);
+        assert!(!socket_path.exists());
+    }
+
+    /// A throwaway certificate and private key in PEM format.
+    struct TestCert {
+        cert_pem: String,
+        key_pem: String,
+    }
+
+    /// Returns a CA certificate, a server certificate for localhost, and a client certificate with
+    /// `client_name` as the common name, both signed by the CA.
+    fn generate_test_certs(client_name: &str) -> (TestCert, TestCert, TestCert) {
+        let mut ca_params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "rustgrpcdemo test CA");
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let ca = rcgen::CertifiedIssuer::self_signed(ca_params, ca_key).unwrap();
+        let ca_cert = TestCert {
+            cert_pem: ca.pem(),
+            key_pem: String::new(),
+        };
+
+        let signed_cert = |params: rcgen::CertificateParams| {
+            let key = rcgen::KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &ca).unwrap();
+            TestCert {
+                cert_pem: cert.pem(),
+                key_pem: key.serialize_pem(),
+            }
+        };
+        let server_cert =
+            signed_cert(rcgen::CertificateParams::new(vec!["localhost".to_string()]).unwrap());
+        let mut client_params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        client_params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, client_name);
+        let client_cert = signed_cert(client_params);
+
+        (ca_cert, server_cert, client_cert)
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let (ca_cert, server_cert, client_cert) = generate_test_certs("test client");
+
+        let tls_config = ServerTlsConfig::new()
+            .identity(Identity::from_pem(
+                &server_cert.cert_pem,
+                &server_cert.key_pem,
+            ))
+            .client_ca_root(Certificate::from_pem(&ca_cert.cert_pem));
+        let shutdown = Shutdown::new();
+        let router = Server::builder()
+            .tls_config(tls_config)
+            .unwrap()
+            .add_service(EchoServer::new(EchoService::new(false, shutdown.clone())));
+        let (grpc_url, _) = start_test_server(router, &shutdown);
+        // the router terminates TLS itself, so only the scheme changes
+        let grpc_url = grpc_url.replacen("http://", "https://", 1);
+
+        let client_tls_config = ClientTlsConfig::new()
+            .domain_name("localhost")
+            .ca_certificate(Certificate::from_pem(&ca_cert.cert_pem));
+        let request = || EchoRequest {
+            input: "hello".to_string(),
+            ..EchoRequest::default()
+        };
+
+        // with a client certificate
+        let channel = rustgrpcdemo::connect(
+            &grpc_url,
+            Some(client_tls_config.clone().identity(Identity::from_pem(
+                &client_cert.cert_pem,
+                &client_cert.key_pem,
+            ))),
+        )
+        .await
+        .unwrap();
+        let response = EchoClient::new(channel).echo(request()).await.unwrap();
+        assert_eq!(response.get_ref().output, "echoed: hello");
+
+        // the server requires a client certificate
+        let result = match rustgrpcdemo::connect(&grpc_url, Some(client_tls_config)).await {
+            Ok(channel) => EchoClient::new(channel).echo(request()).await.map(|_| ()),
+            Err(err) => Err(Status::unavailable(err.to_string())),
+        };
+        result.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_and_aborts_bidi_streams() {
+        let shutdown = Shutdown::new();
+        let router = Server::builder()
+            .add_service(EchoServer::new(EchoService::new(false, shutdown.clone())));
+        let (grpc_url, server) = start_test_server(router, &shutdown);
+
+        // open two streams and wait until the server has echoed a message on each
+        let client = EchoClient::new(rustgrpcdemo::connect(&grpc_url, None).await.unwrap());
+        let open_stream = || async {
+            let (request_sender, request_receiver) = tokio::sync::mpsc::channel(1);
+            let mut response_stream = client
+                .clone()
+                .echo_bi_dir(ReceiverStream::new(request_receiver))
+                .await
+                .unwrap()
+                .into_inner();
+            request_sender
+                .send(EchoRequest {
+                    input: "hello".to_string(),
+                    ..EchoRequest::default()
+                })
+                .await
+                .unwrap();
+            let response = response_stream.message().await.unwrap().unwrap();
+            assert_eq!(response.output, "echoed: hello");
+            (request_sender, response_stream)
+        };
+        let (draining_sender, mut draining_responses) = open_stream().await;
+        let (_aborted_sender, mut aborted_responses) = open_stream().await;
+        assert_eq!(shutdown.summary().active, 2);
+
+        // a stream that ends while draining finishes normally
+        shutdown.start_draining();
+        drop(draining_sender);
+        let bonus_message = draining_responses.message().await.unwrap().unwrap();
+        assert!(bonus_message.output.starts_with("extra message"));
+        assert!(draining_responses.message().await.unwrap().is_none());
+
+        // a stream that is still open when aborting gets UNAVAILABLE
+        shutdown.start_aborting();
+        let status = aborted_responses.message().await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+
+        drop(aborted_responses);
+        drop(client);
+        server.await.unwrap().unwrap();
+        assert_eq!(
+            shutdown.summary(),
+            rustgrpcdemo::shutdown::ShutdownSummary {
+                drained: 1,
+                aborted: 1,
+                active: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_http2_window_size_range() {
+        for flag in [
+            "--http2-stream-window-size",
+            "--http2-connection-window-size",
+        ] {
+            // HTTP/2 windows are at most 2^31-1 bytes
+            assert!(Args::try_parse_from(["rustgrpcdemo", &format!("{flag}=2147483647")]).is_ok());
+            for invalid in ["0", "2147483648"] {
+                let result = Args::try_parse_from(["rustgrpcdemo", &format!("{flag}={invalid}")]);
+                assert!(result.is_err(), "{flag}={invalid}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_concurrent_streams_range() {
+        let parse = |value: &str| {
+            Args::try_parse_from(["rustgrpcdemo", &format!("--max-concurrent-streams={value}")])
+        };
+        assert_eq!(parse("1").unwrap().max_concurrent_streams, Some(1));
+        assert_eq!(
+            parse("4294967295").unwrap().max_concurrent_streams,
+            Some(u32::MAX)
+        );
+        assert!(parse("0").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http2_settings_limit_concurrent_streams() {
+        let args = Args::parse_from([
+            "rustgrpcdemo",
+            "--max-concurrent-streams=1",
+            "--http2-stream-window-size=65535",
+            "--http2-connection-window-size=1048576",
+            "--stream-channel-capacity=8",
+        ]);
+        let shutdown = Shutdown::new();
+        let router = args
+            .server_builder()
+            .unwrap()
+            .add_service(EchoServer::new(args.echo_service(&shutdown).unwrap()));
+        let (grpc_url, _) = start_test_server(router, &shutdown);
+
+        let mut client = EchoClient::new(rustgrpcdemo::connect(&grpc_url, None).await.unwrap());
+        let (request_sender, request_receiver) = tokio::sync::mpsc::channel(1);
+        let mut response_stream = client
+            .echo_bi_dir(ReceiverStream::new(request_receiver))
+            .await
+            .unwrap()
+            .into_inner();
+
+        // the open stream uses the only slot on the connection, so the next RPC waits
+        let mut second_client = client.clone();
+        let mut echo = tokio::spawn(async move {
+            second_client
+                .echo(EchoRequest {
+                    input: "second".to_string(),
+                    ..EchoRequest::default()
+                })
+                .await
+        });
+        let waiting = tokio::time::timeout(Duration::from_millis(100), &mut echo).await;
+        assert!(waiting.is_err(), "{waiting:?}");
+
+        drop(request_sender);
+        while response_stream.message().await.unwrap().is_some() {}
+        let response = echo.await.unwrap().unwrap();
+        assert_eq!(response.get_ref().output, "echoed: second");
+    }
+
+    #[tokio::test]
+    async fn test_health_not_serving_on_shutdown() {
+        use tonic_health::pb::HealthCheckRequest;
+        use tonic_health::pb::health_check_response::ServingStatus;
+        use tonic_health::pb::health_client::HealthClient;
+
+        let shutdown = Shutdown::new();
+        let (health_reporter, health_service) = start_health_service().await;
+        let router = Server::builder().add_service(health_service);
+        let (grpc_url, _) = start_test_server(router, &shutdown);
+
+        let channel = rustgrpcdemo::connect(&grpc_url, None).await.unwrap();
+        let mut client = HealthClient::new(channel);
+        let echo_request = HealthCheckRequest {
+            service: echo_server::SERVICE_NAME.to_string(),
+        };
+        let response = client.check(echo_request.clone()).await.unwrap();
+        assert_eq!(response.get_ref().status(), ServingStatus::Serving);
+
+        let mut watch_stream = client
+            .watch(echo_request.clone())
+            .await
+            .unwrap()
+            .into_inner();
+        let response = watch_stream.message().await.unwrap().unwrap();
+        assert_eq!(response.status(), ServingStatus::Serving);
+
+        report_not_serving(&health_reporter).await;
+        let response = watch_stream.message().await.unwrap().unwrap();
+        assert_eq!(response.status(), ServingStatus::NotServing);
+
+        // the listeners still accept new connections, so new probes see NOT_SERVING
+        let channel = rustgrpcdemo::connect(&grpc_url, None).await.unwrap();
+        let response = HealthClient::new(channel)
+            .check(echo_request)
+            .await
+            .unwrap();
+        assert_eq!(response.get_ref().status(), ServingStatus::NotServing);
+        shutdown.start_draining();
+    }
+
+    #[tokio::test]
+    async fn test_reflection_lists_and_describes_echo() {
+        use tonic_reflection::pb::v1::ServerReflectionRequest;
+        use tonic_reflection::pb::v1::server_reflection_client::ServerReflectionClient;
+        use tonic_reflection::pb::v1::server_reflection_request::MessageRequest;
+        use tonic_reflection::pb::v1::server_reflection_response::MessageResponse;
+
+        let router = Server::builder().add_service(reflection_builder().build_v1().unwrap());
+        let shutdown = Shutdown::new();
+        let (grpc_url, _) = start_test_server(router, &shutdown);
+
+        let channel = rustgrpcdemo::connect(&grpc_url, None).await.unwrap();
+        let mut client = ServerReflectionClient::new(channel);
+        let requests = [
+            MessageRequest::ListServices(String::new()),
+            MessageRequest::FileContainingSymbol("echopb.Echo.EchoBiDir".to_string()),
+        ]
+        .map(|message_request| ServerReflectionRequest {
+            host: String::new(),
+            message_request: Some(message_request),
+        });
+        let mut response_stream = client
+            .server_reflection_info(tokio_stream::iter(requests))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let response = response_stream.message().await.unwrap().unwrap();
+        let Some(MessageResponse::ListServicesResponse(list_response)) = response.message_response
+        else {
+            panic!("unexpected response: {response:?}");
+        };
+        let service_names: Vec<&str> = list_response
+            .service
+            .iter()
+            .map(|service| service.name.as_str())
+            .collect();
+        assert!(service_names.contains(&echo_server::SERVICE_NAME));
+        assert!(service_names.contains(&"grpc.health.v1.Health"));
+
+        let response = response_stream.message().await.unwrap().unwrap();
+        let Some(MessageResponse::FileDescriptorResponse(file_response)) =
+            response.message_response
+        else {
+            panic!("unexpected response: {response:?}");
+        };
+        let file_descriptor = prost_types::FileDescriptorProto::decode(
+            file_response.file_descriptor_proto[0].as_slice(),
+        )
+        .unwrap();
+        let message_names: Vec<&str> = file_descriptor
+            .message_type
+            .iter()
+            .map(prost_types::DescriptorProto::name)
+            .collect();
+        assert_eq!(
+            message_names,
+            [
+                "EchoRequest",
+                "EchoResponse",
+                "EchoRepeatRequest",
+                "EchoCollectResponse",
+                "Example1",
+                "Example2"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_custom_codec_echo_and_bi_dir() {
+        let shutdown = Shutdown::new();
+        // buffers much smaller than the messages
+        let router = Server::builder().add_service(WithCodecBufferSettings::new(
+            CustomCodecEchoServer::new(EchoService::new(false, shutdown.clone())),
+            CustomCodecBufferSettings {
+                buffer_size: 64,
+                yield_threshold: 128,
+            },
+        ));
+        let (grpc_url, _) = start_test_server(router, &shutdown);
+
+        // the custom codec uses the protobuf encoding so the normal client works
+        let mut client = EchoClient::new(rustgrpcdemo::connect(&grpc_url, None).await.unwrap());
+        let large_input = "x".repeat(100_000);
+        let response = client
+            .echo(EchoRequest {
+                input: large_input.clone(),
+                ..EchoRequest::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.get_ref().output, format!("echoed: {large_input}"));
+
+        let requests = ["a", "b"].map(|input| EchoRequest {
+            input: input.to_string(),
+            ..EchoRequest::default()
+        });
+        let mut response_stream = client
+            .echo_bi_dir(tokio_stream::iter(requests))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut outputs = vec![];
+        while let Some(response) = response_stream.message().await.unwrap() {
+            outputs.push(response.output);
+        }
+        assert_eq!(
+            outputs,
+            [
+                "echoed: a",
+                "echoed: b",
+                "extra message after sender closed abcdef"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json_codec_echo_and_bi_dir() {
+        use rustgrpcdemo::json_codec::JSON_CONTENT_TYPE;
+        use rustgrpcdemo::json_codec::json_channel;
+        use rustgrpcdemo::json_echopb::echo_client::EchoClient as JsonEchoClient;
+
+        let shutdown = Shutdown::new();
+        let echo_service = Arc::new(EchoService::new(false, shutdown.clone()));
+        let router = Server::builder()
+            .add_service(EchoServer::from_arc(echo_service.clone()))
+            .add_service(JsonContentType::new(JsonEchoServer::from_arc(echo_service)));
+        let (grpc_url, _) = start_test_server(router, &shutdown);
+
+        let channel = rustgrpcdemo::connect(&grpc_url, None).await.unwrap();
+        let mut client = JsonEchoClient::new(json_channel(channel.clone()));
+        let response = client
+            .echo(EchoRequest {
+                input: "hello json".to_string(),
+                ..EchoRequest::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            response.metadata().get("content-type").unwrap(),
+            JSON_CONTENT_TYPE
+        );
+        assert_eq!(response.get_ref().output, "echoed: hello json");
+
+        let requests = ["a", "b"].map(|input| EchoRequest {
+            input: input.to_string(),
+            ..EchoRequest::default()
+        });
+        let mut response_stream = client
+            .echo_bi_dir(tokio_stream::iter(requests))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut outputs = vec![];
+        while let Some(response) = response_stream.message().await.unwrap() {
+            outputs.push(response.output);
+        }
+        assert_eq!(
+            outputs,
+            [
+                "echoed: a",
+                "echoed: b",
+                "extra message after sender closed abcdef"
+            ]
+        );
+
+        // the protobuf service on the same server is unchanged
+        let response = EchoClient::new(channel)
+            .echo(EchoRequest {
+                input: "hello protobuf".to_string(),
+                ..EchoRequest::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            response.metadata().get("content-type").unwrap(),
+            "application/grpc"
+        );
+        assert_eq!(response.get_ref().output, "echoed: hello protobuf");
+    }
+
+    #[tokio::test]
+    async fn test_raw_codec_all_rpcs() {
+        let shutdown = Shutdown::new();
+        let router = Server::builder().add_service(
+            rustgrpcdemo::raw_codec_echopb::echo_server::EchoServer::new(EchoService::new(
+                false,
+                shutdown.clone(),
+            )),
+        );
+        let (grpc_url, _) = start_test_server(router, &shutdown);
+
+        let channel = rustgrpcdemo::connect(&grpc_url, None).await.unwrap();
+        let mut client = EchoClient::new(channel.clone());
+        let response = client
+            .echo(EchoRequest {
+                input: "hello raw".to_string(),
+                ..EchoRequest::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.get_ref().output, "echoed: hello raw");
+
+        let requests = ["a", "b"].map(|input| EchoRequest {
+            input: input.to_string(),
+            ..EchoRequest::default()
+        });
+        let mut response_stream = client
+            .echo_bi_dir(tokio_stream::iter(requests))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut outputs = vec![];
+        while let Some(response) = response_stream.message().await.unwrap() {
+            outputs.push(response.output);
+        }
+        assert_eq!(
+            outputs,
+            [
+                "echoed: a",
+                "echoed: b",
+                "extra message after sender closed abcdef"
+            ]
+        );
+
+        let mut response_stream = client
+            .echo_repeat(rustgrpcdemo::echopb::EchoRepeatRequest {
+                input: "again".to_string(),
+                count: 2,
+                interval_ms: 1,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let mut outputs = vec![];
+        while let Some(response) = response_stream.message().await.unwrap() {
+            outputs.push(response.output);
+        }
+        assert_eq!(outputs, ["echoed: again", "echoed: again"]);
+
+        let requests = ["a", "b"].map(|input| EchoRequest {
+            input: input.to_string(),
+            ..EchoRequest::default()
+        });
+        let response = client
+            .echo_collect(tokio_stream::iter(requests))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.count, 2);
+        assert_eq!(response.output, "echoed: a b");
+
+        // a raw client can send bytes that are not a valid EchoRequest
+        let mut raw_client = rustgrpcdemo::raw_codec_echopb::echo_client::EchoClient::new(channel);
+        let status = raw_client
+            .echo(bytes::Bytes::from_static(b"\xff\xff"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(
+            status.message().contains("not a valid echopb.EchoRequest"),
+            "{status:?}"
+        );
+
+        let mut response_stream = raw_client
+            .echo_bi_dir(tokio_stream::iter([bytes::Bytes::from_static(b"\xff\xff")]))
+            .await
+            .unwrap()
+            .into_inner();
+        let status = response_stream.message().await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument, "{status:?}");
+        assert!(
+            status.message().contains("not a valid echopb.EchoRequest"),
+            "{status:?}"
+        );
+    }
+
+    /// Returns `channel` wrapped to send requests with `content_type`.
+    fn with_content_type(
+        channel: tonic::transport::Channel,
+        content_type: &'static str,
+    ) -> tower::util::MapRequest<
+        tonic::transport::Channel,
+        impl Fn(http::Request<tonic::body::Body>) -> http::Request<tonic::body::Body> + Clone,
+    > {
+        tower::util::MapRequest::new(channel, move |mut request: http::Request<_>| {
+            request.headers_mut().insert(
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_static(content_type),
+            );
+            request
+        })
+    }
+
+    #[tokio::test]
+    async fn test_codec_registry_negotiates_content_type() {
+        use rustgrpcdemo::json_codec::json_channel;
+        use rustgrpcdemo::json_codec_echopb::echo_client::EchoClient as JsonCodecEchoClient;
+
+        let shutdown = Shutdown::new();
+        let echo_service = Arc::new(EchoService::new(false, shutdown.clone()));
+        let router = Server::builder().add_service(register_echo_codecs(
+            EchoServer::from_arc(echo_service.clone()),
+            &echo_service,
+            CustomCodecBufferSettings::default(),
+            Compression::None,
+        ));
+        let (grpc_url, _) = start_test_server(router, &shutdown);
+        let channel = rustgrpcdemo::connect(&grpc_url, None).await.unwrap();
+
+        for content_type in [
+            "application/grpc",
+            "application/grpc+proto",
+            "application/grpc+custom",
+        ] {
+            let mut client = EchoClient::new(with_content_type(channel.clone(), content_type));
+            let response = client
+                .echo(EchoRequest {
+                    input: content_type.to_string(),
+                    ..EchoRequest::default()
+                })
+                .await
+                .unwrap();
+            assert_eq!(
+                response.metadata().get("content-type").unwrap(),
+                content_type
+            );
+            assert_eq!(response.get_ref().output, format!("echoed: {content_type}"));
+        }
+
+        let mut json_client = JsonCodecEchoClient::new(json_channel(channel.clone()));
+        let response = json_client
+            .echo(EchoRequest {
+                input: "json".to_string(),
+                ..EchoRequest::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            response.metadata().get("content-type").unwrap(),
+            "application/grpc+json"
+        );
+        assert_eq!(response.get_ref().output, "echoed: json");
+
+        let mut client = EchoClient::new(with_content_type(
+            channel.clone(),
+            "application/grpc+unknown",
+        ));
+        let status = client.echo(EchoRequest::default()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unimplemented, "{status:?}");
+
+        let mut client = EchoClient::new(with_content_type(channel, "application/json"));
+        let status = client.echo(EchoRequest::default()).await.unwrap_err();
+        assert!(status.message().contains("415"), "{status:?}");
+    }
+
+    #[tokio::test]
+    async fn test_compression_negotiation() {
+        use rustgrpcdemo::compression::echo_client;
+
+        let shutdown = Shutdown::new();
+        let echo_service = Arc::new(EchoService::new(false, shutdown.clone()));
+        let router = Server::builder().add_service(register_echo_codecs(
+            with_compression!(
+                EchoServer::from_arc(echo_service.clone()),
+                Compression::Zstd
+            ),
+            &echo_service,
+            CustomCodecBufferSettings::default(),
+            Compression::Zstd,
+        ));
+        let (grpc_url, _) = start_test_server(router, &shutdown);
+        let channel = rustgrpcdemo::connect(&grpc_url, None).await.unwrap();
+
+        // the server accepts any request compression, and compresses responses with the
+        // client's preferred encoding
+        let large_input = "repetitive ".repeat(10_000);
+        for (compression, response_encoding) in [
+            (Compression::None, "gzip"),
+            (Compression::Gzip, "gzip"),
+            (Compression::Zstd, "zstd"),
+        ] {
+            let response = echo_client(channel.clone(), compression)
+                .echo(EchoRequest {
+                    input: large_input.clone(),
+                    ..EchoRequest::default()
+                })
+                .await
+                .unwrap();
+            assert_eq!(
+                response.metadata().get("grpc-encoding").unwrap(),
+                response_encoding
+            );
+            assert_eq!(response.get_ref().output, format!("echoed: {large_input}"));
+        }
+
+        // clients that do not accept compression get uncompressed responses
+        let response = EchoClient::new(channel)
+            .echo(EchoRequest {
+                input: large_input.clone(),
+                ..EchoRequest::default()
+            })
+            .await
+            .unwrap();
+        assert!(response.metadata().get("grpc-encoding").is_none());
+        assert_eq!(response.get_ref().output, format!("echoed: {large_input}"));
+    }
+
+    #[tokio::test]
+    async fn test_echo_metadata_in_headers_and_trailers() {
+        use rustgrpcdemo::echo_metadata::ECHO_METADATA_HEADER;
+        use tonic::metadata::MetadataValue;
+
+        let shutdown = Shutdown::new();
+        let echo_service = Arc::new(EchoService::new(false, shutdown.clone()));
+        let router = Server::builder().add_service(register_echo_codecs(
+            EchoServer::from_arc(echo_service.clone()),
+            &echo_service,
+            CustomCodecBufferSettings::default(),
+            Compression::None,
+        ));
+        let (grpc_url, _) = start_test_server(router, &shutdown);
+        let mut client = EchoClient::new(rustgrpcdemo::connect(&grpc_url, None).await.unwrap());
+
+        let mut request = tonic::Request::new(tokio_stream::iter([EchoRequest::default()]));
+        let metadata = request.metadata_mut();
+        metadata.insert(ECHO_METADATA_HEADER, "x-test, x-test-bin".parse().unwrap());
+        metadata.insert("x-test", "hello".parse().unwrap());
+        metadata.insert_bin("x-test-bin", MetadataValue::from_bytes(b"\x00\x01"));
+        metadata.insert("x-not-selected", "nope".parse().unwrap());
+        let response = client.echo_bi_dir(request).await.unwrap();
+        assert_eq!(response.metadata().get("x-test").unwrap(), "hello");
+        assert!(response.metadata().get("x-not-selected").is_none());
+
+        let mut response_stream = response.into_inner();
+        while response_stream.message().await.unwrap().is_some() {}
+        let trailers = response_stream.trailers().await.unwrap().unwrap();
+        assert_eq!(trailers.get("x-test").unwrap(), "hello");
+        assert_eq!(
+            trailers.get_bin("x-test-bin").unwrap().to_bytes().unwrap(),
+            &b"\x00\x01"[..]
+        );
+        assert!(trailers.get("x-not-selected").is_none());
+
+        // requests without x-echo-metadata do not echo anything
+        let mut request = tonic::Request::new(EchoRequest::default());
+        request
+            .metadata_mut()
+            .insert("x-test", "hello".parse().unwrap());
+        let response = client.echo(request).await.unwrap();
+        assert!(response.metadata().get("x-test").is_none());
+    }
 }
@@ -0,0 +1,293 @@
+//! Builds and decodes errors with details, using the gRPC rich error model.
+//!
+//! The details are encoded as `google.protobuf.Any` messages in a `google.rpc.Status`, which is
+//! sent in the `grpc-status-details-bin` trailer. See <https://cloud.google.com/apis/design/errors>.
+
+use prost::DecodeError;
+use prost::Message;
+use prost::Name;
+use prost_types::Any;
+use tonic::Code;
+use tonic::Status;
+use tonic_types::BadRequest;
+use tonic_types::DebugInfo;
+use tonic_types::ErrorInfo;
+use tonic_types::LocalizedMessage;
+use tonic_types::QuotaFailure;
+use tonic_types::ResourceInfo;
+use tonic_types::RetryInfo;
+use tonic_types::pb;
+
+/// Builds a [`Status`] with any mix of the standard `google.rpc` error details and other
+/// messages.
+#[derive(Debug, Clone)]
+pub struct RichErrorBuilder {
+    code: Code,
+    message: String,
+    details: Vec<Any>,
+}
+
+impl RichErrorBuilder {
+    #[must_use]
+    pub fn new(code: Code, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            details: vec![],
+        }
+    }
+
+    /// Adds a `google.rpc.BadRequest` detail.
+    #[must_use]
+    pub fn bad_request(self, bad_request: BadRequest) -> Self {
+        self.standard_detail::<pb::BadRequest>(BadRequest::TYPE_URL, bad_request)
+    }
+
+    /// Adds a `google.rpc.RetryInfo` detail.
+    #[must_use]
+    pub fn retry_info(self, retry_info: RetryInfo) -> Self {
+        self.standard_detail::<pb::RetryInfo>(RetryInfo::TYPE_URL, retry_info)
+    }
+
+    /// Adds a `google.rpc.ErrorInfo` detail.
+    #[must_use]
+    pub fn error_info(self, error_info: ErrorInfo) -> Self {
+        self.standard_detail::<pb::ErrorInfo>(ErrorInfo::TYPE_URL, error_info)
+    }
+
+    /// Adds a `google.rpc.QuotaFailure` detail.
+    #[must_use]
+    pub fn quota_failure(self, quota_failure: QuotaFailure) -> Self {
+        self.standard_detail::<pb::QuotaFailure>(QuotaFailure::TYPE_URL, quota_failure)
+    }
+
+    /// Adds a `google.rpc.DebugInfo` detail.
+    #[must_use]
+    pub fn debug_info(self, debug_info: DebugInfo) -> Self {
+        self.standard_detail::<pb::DebugInfo>(DebugInfo::TYPE_URL, debug_info)
+    }
+
+    /// Adds a `google.rpc.LocalizedMessage` detail.
+    #[must_use]
+    pub fn localized_message(self, localized_message: LocalizedMessage) -> Self {
+        self.standard_detail::<pb::LocalizedMessage>(LocalizedMessage::TYPE_URL, localized_message)
+    }
+
+    /// Adds a `google.rpc.ResourceInfo` detail.
+    #[must_use]
+    pub fn resource_info(self, resource_info: ResourceInfo) -> Self {
+        self.standard_detail::<pb::ResourceInfo>(ResourceInfo::TYPE_URL, resource_info)
+    }
+
+    /// Adds any message with a type URL, such as [`crate::echopb::Example1`].
+    #[must_use]
+    pub fn detail<M: Name>(mut self, message: &M) -> Self {
+        // Any::from_msg returns a Result, but encoding to a Vec cannot fail
+        self.details.push(Any {
+            type_url: M::type_url(),
+            value: message.encode_to_vec(),
+        });
+        self
+    }
+
+    /// Adds a standard detail: tonic-types converts them to their protobuf messages, which do
+    /// not implement [`Name`].
+    fn standard_detail<P: Message>(mut self, type_url: &str, detail: impl Into<P>) -> Self {
+        self.details.push(Any {
+            type_url: type_url.to_string(),
+            value: detail.into().encode_to_vec(),
+        });
+        self
+    }
+
+    /// Returns the status with the details attached.
+    #[must_use]
+    pub fn build(self) -> Status {
+        let status_pb = tonic_types::Status {
+            code: self.code as i32,
+            message: self.message.clone(),
+            details: self.details,
+        };
+        Status::with_details(self.code, self.message, status_pb.encode_to_vec().into())
+    }
+}
+
+/// The reasons the `grpc-status-details-bin` trailer of a status cannot be used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DetailsError {
+    /// The trailer is not a valid `google.rpc.Status`.
+    Malformed(DecodeError),
+    /// The code in the trailer is not the code in `grpc-status`.
+    CodeMismatch {
+        status_code: Code,
+        details_code: i32,
+    },
+    /// The message in the trailer is not the message in `grpc-message`.
+    MessageMismatch {
+        status_message: String,
+        details_message: String,
+    },
+}
+
+impl std::fmt::Display for DetailsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed(err) => write!(f, "malformed grpc-status-details-bin: {err}"),
+            Self::CodeMismatch {
+                status_code,
+                details_code,
+            } => write!(
+                f,
+                "grpc-status-details-bin code={details_code} does not match grpc-status={}",
+                *status_code as i32
+            ),
+            Self::MessageMismatch {
+                status_message,
+                details_message,
+            } => write!(
+                f,
+                "grpc-status-details-bin message={details_message:?} does not match \
+                 grpc-message={status_message:?}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DetailsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Malformed(err) => Some(err),
+            Self::CodeMismatch { .. } | Self::MessageMismatch { .. } => None,
+        }
+    }
+}
+
+/// Returns the details from the `grpc-status-details-bin` trailer of `status`.
+///
+/// Returns an empty `Vec` if it has none. The trailer must have the same code and message as
+/// `status`. See: <https://github.com/grpc/grpc/blob/master/doc/PROTOCOL-HTTP2.md>
+pub fn decode_details(status: &Status) -> Result<Vec<Any>, DetailsError> {
+    if status.details().is_empty() {
+        return Ok(vec![]);
+    }
+    let status_pb =
+        tonic_types::Status::decode(status.details()).map_err(DetailsError::Malformed)?;
+    if status_pb.code != status.code() as i32 {
+        return Err(DetailsError::CodeMismatch {
+            status_code: status.code(),
+            details_code: status_pb.code,
+        });
+    }
+    if status_pb.message != status.message() {
+        return Err(DetailsError::MessageMismatch {
+            status_message: status.message().to_string(),
+            details_message: status_pb.message,
+        });
+    }
+    Ok(status_pb.details)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tonic_types::StatusExt;
+
+    use super::*;
+    use crate::echopb::Example1;
+
+    #[test]
+    fn test_rich_error_builder() {
+        let status = RichErrorBuilder::new(Code::InvalidArgument, "bad input")
+            .bad_request(BadRequest::with_violation("input", "must not be empty"))
+            .retry_info(RetryInfo::new(Some(Duration::from_secs(2))))
+            .detail(&Example1 { int64_value: 99 })
+            .error_info(ErrorInfo::new(
+                "EMPTY_INPUT",
+                "echo.example.com",
+                std::collections::HashMap::new(),
+            ))
+            .build();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), "bad input");
+
+        let error_details = status.check_error_details().unwrap();
+        assert_eq!(
+            error_details.bad_request().unwrap().field_violations[0].field,
+            "input"
+        );
+        assert_eq!(
+            error_details.retry_info().unwrap().retry_delay,
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(error_details.error_info().unwrap().reason, "EMPTY_INPUT");
+
+        let status_pb = tonic_types::Status::decode(status.details()).unwrap();
+        assert_eq!(status_pb.code, Code::InvalidArgument as i32);
+        assert_eq!(status_pb.details.len(), 4);
+        assert_eq!(
+            status_pb.details[2].to_msg::<Example1>().unwrap(),
+            Example1 { int64_value: 99 }
+        );
+        assert_eq!(decode_details(&status).unwrap(), status_pb.details);
+    }
+
+    #[test]
+    fn test_decode_details() {
+        // From a Go server
+        const DETAILS_BYTES_HEX: &[u8] = &[
+            0x08, 0x0D, 0x12, 0x14, 0x65, 0x72, 0x72, 0x6F, 0x72, 0x20, 0x77, 0x69, 0x74, 0x68,
+            0x20, 0x32, 0x20, 0x64, 0x65, 0x74, 0x61, 0x69, 0x6C, 0x73, 0x1A, 0x29, 0x0A, 0x23,
+            0x74, 0x79, 0x70, 0x65, 0x2E, 0x67, 0x6F, 0x6F, 0x67, 0x6C, 0x65, 0x61, 0x70, 0x69,
+            0x73, 0x2E, 0x63, 0x6F, 0x6D, 0x2F, 0x65, 0x63, 0x68, 0x6F, 0x70, 0x62, 0x2E, 0x45,
+            0x78, 0x61, 0x6D, 0x70, 0x6C, 0x65, 0x31, 0x12, 0x02, 0x08, 0x63, 0x1A, 0x30, 0x0A,
+            0x23, 0x74, 0x79, 0x70, 0x65, 0x2E, 0x67, 0x6F, 0x6F, 0x67, 0x6C, 0x65, 0x61, 0x70,
+            0x69, 0x73, 0x2E, 0x63, 0x6F, 0x6D, 0x2F, 0x65, 0x63, 0x68, 0x6F, 0x70, 0x62, 0x2E,
+            0x45, 0x78, 0x61, 0x6D, 0x70, 0x6C, 0x65, 0x32, 0x12, 0x09, 0x09, 0x1F, 0x85, 0xEB,
+            0x51, 0xB8, 0x1E, 0x09, 0x40,
+        ];
+        let details_bytes = bytes::Bytes::from_static(DETAILS_BYTES_HEX);
+
+        let status = Status::with_details(
+            Code::Internal,
+            "error with 2 details",
+            details_bytes.clone(),
+        );
+        let details = decode_details(&status).unwrap();
+        assert_eq!(2, details.len());
+        assert_eq!(details[0].type_url, crate::echopb::Example1::type_url());
+        assert_eq!(details[1].type_url, crate::echopb::Example2::type_url());
+
+        assert_eq!(
+            decode_details(&Status::internal("no details")).unwrap(),
+            vec![]
+        );
+        assert!(matches!(
+            decode_details(&Status::with_details(
+                Code::Internal,
+                "error with 2 details",
+                details_bytes.slice(..10),
+            )),
+            Err(DetailsError::Malformed(_))
+        ));
+        assert_eq!(
+            decode_details(&Status::with_details(
+                Code::Unavailable,
+                "error with 2 details",
+                details_bytes.clone(),
+            )),
+            Err(DetailsError::CodeMismatch {
+                status_code: Code::Unavailable,
+                details_code: 13
+            })
+        );
+        assert!(matches!(
+            decode_details(&Status::with_details(
+                Code::Internal,
+                "other message",
+                details_bytes
+            )),
+            Err(DetailsError::MessageMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,204 @@
+//! Graceful server shutdown: stop accepting connections, let in-flight RPCs drain for a grace
+//! period, then abort the RPCs that are still running.
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use tokio::sync::watch;
+
+/// The phases of a server shutdown, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ShutdownState {
+    /// Accepting connections and RPCs normally.
+    Serving,
+    /// No longer accepting connections. In-flight RPCs may continue until they finish.
+    Draining,
+    /// The grace period expired. In-flight RPCs should end as soon as possible.
+    Aborting,
+}
+
+/// Counts of RPCs that were in flight when the shutdown started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    /// RPCs that finished normally after the shutdown started.
+    pub drained: usize,
+    /// RPCs that were ended by the server after the grace period expired.
+    pub aborted: usize,
+    /// RPCs that were still running when the summary was created.
+    pub active: usize,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: watch::Sender<ShutdownState>,
+    active: watch::Sender<usize>,
+    drained: AtomicUsize,
+    aborted: AtomicUsize,
+}
+
+/// Coordinates the shutdown between the server and its services. Clones share the same state.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                state: watch::Sender::new(ShutdownState::Serving),
+                active: watch::Sender::new(0),
+                drained: AtomicUsize::new(0),
+                aborted: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    #[must_use]
+    pub fn state(&self) -> ShutdownState {
+        *self.inner.state.borrow()
+    }
+
+    /// Stops accepting new connections and lets in-flight RPCs drain.
+    pub fn start_draining(&self) {
+        self.advance_to(ShutdownState::Draining);
+    }
+
+    /// Tells in-flight RPCs to end as soon as possible.
+    pub fn start_aborting(&self) {
+        self.advance_to(ShutdownState::Aborting);
+    }
+
+    fn advance_to(&self, new_state: ShutdownState) {
+        self.inner.state.send_if_modified(|state| {
+            if *state < new_state {
+                *state = new_state;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    /// Waits until the shutdown reaches at least `state`.
+    async fn wait_for_state(&self, state: ShutdownState) {
+        let mut receiver = self.inner.state.subscribe();
+        // the sender is owned by self so it cannot be dropped while waiting
+        let _ = receiver.wait_for(|current| *current >= state).await;
+    }
+
+    /// Waits until the server starts draining.
+    pub async fn draining(&self) {
+        self.wait_for_state(ShutdownState::Draining).await;
+    }
+
+    /// Waits until the grace period expires and in-flight RPCs should be aborted.
+    pub async fn aborting(&self) {
+        self.wait_for_state(ShutdownState::Aborting).await;
+    }
+
+    /// Waits until no RPCs are in flight.
+    pub async fn idle(&self) {
+        let mut receiver = self.inner.active.subscribe();
+        let _ = receiver.wait_for(|active| *active == 0).await;
+    }
+
+    /// Tracks an RPC until the returned guard is dropped.
+    #[must_use]
+    pub fn start_rpc(&self) -> RpcGuard {
+        self.inner.active.send_modify(|active| *active += 1);
+        RpcGuard {
+            shutdown: self.clone(),
+            aborted: false,
+        }
+    }
+
+    #[must_use]
+    pub fn summary(&self) -> ShutdownSummary {
+        ShutdownSummary {
+            drained: self.inner.drained.load(Ordering::Relaxed),
+            aborted: self.inner.aborted.load(Ordering::Relaxed),
+            active: *self.inner.active.borrow(),
+        }
+    }
+}
+
+/// Tracks an in-flight RPC. Dropping it marks the RPC as finished.
+#[derive(Debug)]
+pub struct RpcGuard {
+    shutdown: Shutdown,
+    aborted: bool,
+}
+
+impl RpcGuard {
+    /// Records that the server ended this RPC because of the shutdown.
+    pub const fn abort(&mut self) {
+        self.aborted = true;
+    }
+}
+
+impl Drop for RpcGuard {
+    fn drop(&mut self) {
+        let inner = &self.shutdown.inner;
+        if self.shutdown.state() != ShutdownState::Serving {
+            let counter = if self.aborted {
+                &inner.aborted
+            } else {
+                &inner.drained
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        inner.active.send_modify(|active| *active -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_counts_drained_and_aborted() {
+        let shutdown = Shutdown::new();
+        drop(shutdown.start_rpc());
+        let drained_rpc = shutdown.start_rpc();
+        let mut aborted_rpc = shutdown.start_rpc();
+
+        shutdown.start_draining();
+        shutdown.draining().await;
+        drop(drained_rpc);
+
+        shutdown.start_aborting();
+        shutdown.aborting().await;
+        // draining after aborting does not go backwards
+        shutdown.start_draining();
+        assert_eq!(shutdown.state(), ShutdownState::Aborting);
+        assert_eq!(
+            shutdown.summary(),
+            ShutdownSummary {
+                drained: 1,
+                aborted: 0,
+                active: 1
+            }
+        );
+
+        aborted_rpc.abort();
+        drop(aborted_rpc);
+        shutdown.idle().await;
+        assert_eq!(
+            shutdown.summary(),
+            ShutdownSummary {
+                drained: 1,
+                aborted: 1,
+                active: 0
+            }
+        );
+    }
+}